@@ -0,0 +1,339 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::path::PathBuf;
+use std::str;
+use cgrc::cgrcconfmanager::CGRCConfManager;
+use cgrc::cgrcconfstorage::{load_confs, load_conf_map};
+use cgrc::cgrcdata::{CgrcConf, CgrcConfItem, CgrcCountMode, CONF_KEYS};
+use cgrc::cgrcerror::{CgrcConfDiagnostic, CgrcConfError, CgrcConfErrorKind, CgrcConfSeverity};
+use cgrc::cgrcparser::CGRCParser;
+
+///
+/// Lines of the rule being checked, used to point at the problems.
+///
+struct CgrcRuleLines<'a> {
+    regexp: Option<(usize, &'a str)>,
+    colours: Option<(usize, &'a str)>,
+}
+
+pub struct CGRCChecker {}
+
+impl CGRCChecker {
+    ///
+    /// Checks the configurations and prints the problems found to stdout.
+    /// When all is set, every embedded, user and system configuration is
+    /// checked, together with the command maps. Returns the exit code.
+    ///
+    pub fn run(confs: &[String], local_path: bool, all: bool) -> i32 {
+        let mut diagnostics: Vec<CgrcConfDiagnostic> = vec![];
+        let mut checked = 0;
+        for conf in confs {
+            checked += 1;
+            match CGRCConfManager::load_conf(conf, local_path) {
+                None => diagnostics.push(CgrcConfDiagnostic {
+                    severity: CgrcConfSeverity::CgrcSeverityError,
+                    error: CgrcConfError::new(CgrcConfErrorKind::CgrcErrorIo(std::io::ErrorKind::NotFound.into()), conf)
+                }),
+                Some(v) => diagnostics.append(&mut Self::check_conf_string(&v, conf))
+            }
+        }
+
+        if all {
+            let confs = load_confs();
+            let mut names: Vec<&&str> = confs.keys().collect();
+            names.sort();
+            for name in names {
+                checked += 1;
+                match str::from_utf8(confs[*name]) {
+                    Err(e) => log::error!("Error: {}. Cannot read conf {}", e, name),
+                    Ok(v) => diagnostics.append(&mut Self::check_conf_string(v, name))
+                }
+            }
+
+            let mut paths: Vec<String> = vec![];
+            if let Some(user_path) = CGRCConfManager::default_user_path() {
+                paths.push(user_path);
+            }
+            paths.push(CGRCConfManager::default_system_path().to_string());
+            for path in &paths {
+                let mut files = CGRCConfManager::conf_files_in_path(path);
+                files.sort();
+                for file in files {
+                    checked += 1;
+                    match fs::read_to_string(&file) {
+                        Err(e) => diagnostics.push(CgrcConfDiagnostic {
+                            severity: CgrcConfSeverity::CgrcSeverityError,
+                            error: CgrcConfError::new(CgrcConfErrorKind::CgrcErrorIo(e), &file)
+                        }),
+                        Ok(v) => diagnostics.append(&mut Self::check_conf_string(&v, &file))
+                    }
+                }
+            }
+
+            for path in &paths {
+                let map_path = PathBuf::from(path).join(CGRCConfManager::conf_map_file_name());
+                if !map_path.exists() {
+                    continue;
+                }
+                checked += 1;
+                let source = map_path.display().to_string();
+                match fs::read_to_string(&map_path) {
+                    Err(e) => diagnostics.push(CgrcConfDiagnostic {
+                        severity: CgrcConfSeverity::CgrcSeverityError,
+                        error: CgrcConfError::new(CgrcConfErrorKind::CgrcErrorIo(e), &source)
+                    }),
+                    Ok(v) => diagnostics.append(&mut Self::check_conf_map_string(&v, &source))
+                }
+            }
+
+            checked += 1;
+            if let Ok(v) = str::from_utf8(load_conf_map()) {
+                diagnostics.append(&mut Self::check_conf_map_string(v, CGRCConfManager::conf_map_file_name()));
+            }
+        }
+
+        for diagnostic in &diagnostics {
+            println!("{}", diagnostic);
+            println!();
+        }
+
+        let errors = diagnostics.iter()
+            .filter(|d| d.severity == CgrcConfSeverity::CgrcSeverityError)
+            .count();
+        let warnings = diagnostics.len() - errors;
+        println!("Checked {} configurations: {} errors, {} warnings", checked, errors, warnings);
+
+        if errors > 0 { 1 } else { 0 }
+    }
+
+    ///
+    /// Checks a configuration, returning all the problems found.
+    ///
+    pub fn check_conf_string(conf: &str, source: &str) -> Vec<CgrcConfDiagnostic> {
+        let mut diagnostics: Vec<CgrcConfDiagnostic> = vec![];
+        let mut parsed_conf = CgrcConf::new();
+        let stack = [source.to_string()];
+        let mut item = CgrcConfItem::new();
+        let mut rule_lines = CgrcRuleLines {
+            regexp: None,
+            colours: None
+        };
+        for (index, line) in conf.lines().enumerate() {
+            let line_number = index + 1;
+            let mut push = |severity: CgrcConfSeverity, error: CgrcConfError| {
+                diagnostics.push(CgrcConfDiagnostic {
+                    severity,
+                    error: error.at(source, line_number)
+                });
+            };
+
+            let lline = line.to_lowercase();
+            if let Some((key, value)) = lline.split_once('=') {
+                let value_column = key.chars().count() + 1;
+                if !key.is_empty()
+                    && !key.starts_with('#')
+                    && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    && !CONF_KEYS.contains(&key) {
+                    push(CgrcConfSeverity::CgrcSeverityWarning, CgrcConfError::in_line(
+                        CgrcConfErrorKind::CgrcErrorUnknownKey(key.to_string()), line, 0, key.chars().count()));
+                }
+
+                if key == "regexp" {
+                    rule_lines.regexp = Some((line_number, line));
+                }
+
+                if key == "colours" {
+                    rule_lines.colours = Some((line_number, line));
+                    for unknown in CGRCParser::parse_colors(value).1 {
+                        let column = match value.find(&unknown) {
+                            None => value_column,
+                            Some(v) => value_column + value[..v].chars().count()
+                        };
+                        push(CgrcConfSeverity::CgrcSeverityError, CgrcConfError::in_line(
+                            CgrcConfErrorKind::CgrcErrorUnknownColor(unknown.clone()), line, column, unknown.chars().count()));
+                    }
+                }
+
+                if key == "skip" && value != "yes" && value != "no" {
+                    push(CgrcConfSeverity::CgrcSeverityWarning, CgrcConfError::in_line(
+                        CgrcConfErrorKind::CgrcErrorInvalidSkip(value.to_string()), line, value_column, value.chars().count()));
+                }
+            }
+
+            // Included rules are added by the parser, so the rule being
+            // checked is complete.
+            if (lline.starts_with("include=") || lline.starts_with("extends=")) && item.regex.is_some() {
+                Self::check_rule(&item, &rule_lines, source, &mut diagnostics);
+                item = CgrcConfItem::new();
+                rule_lines = CgrcRuleLines {
+                    regexp: None,
+                    colours: None
+                };
+            }
+
+            match CGRCParser::parse_conf_line(line, &mut parsed_conf, &mut item, &stack) {
+                Err(e) => diagnostics.push(CgrcConfDiagnostic {
+                    severity: CgrcConfSeverity::CgrcSeverityError,
+                    error: e.at(source, line_number)
+                }),
+                Ok(true) => {
+                    Self::check_rule(&item, &rule_lines, source, &mut diagnostics);
+                    item = CgrcConfItem::new();
+                    rule_lines = CgrcRuleLines {
+                        regexp: None,
+                        colours: None
+                    };
+                },
+                Ok(false) => {}
+            }
+        }
+
+        if item.regex.is_some() {
+            Self::check_rule(&item, &rule_lines, source, &mut diagnostics);
+        }
+
+        diagnostics.sort_by_key(|d| d.error.line_number);
+        diagnostics
+    }
+
+    ///
+    /// Checks a map from command lines to configurations.
+    ///
+    pub fn check_conf_map_string(conf_map: &str, source: &str) -> Vec<CgrcConfDiagnostic> {
+        match CGRCParser::parse_conf_map_string(conf_map, source) {
+            Ok(_) => vec![],
+            Err(e) => vec![CgrcConfDiagnostic {
+                severity: CgrcConfSeverity::CgrcSeverityError,
+                error: e
+            }]
+        }
+    }
+
+    // Private portion
+    // ===============
+    fn check_rule(item: &CgrcConfItem, rule_lines: &CgrcRuleLines, source: &str, diagnostics: &mut Vec<CgrcConfDiagnostic>) {
+        let (regex, (regexp_number, regexp_line)) = match (&item.regex, rule_lines.regexp) {
+            (Some(r), Some(l)) => (r, l),
+            _ => return
+        };
+
+        let regexp_column = "regexp=".len();
+        let regexp_width = regexp_line.chars().count() - regexp_column;
+        let captures = regex.captures_len();
+        let colors = item.colors.len();
+        let inherits_colors = item.count_mode == Some(CgrcCountMode::CgrcCountPrevious);
+        if colors == 0 {
+            if !item.skip.unwrap_or(false) && item.replace.is_none() && !inherits_colors {
+                diagnostics.push(CgrcConfDiagnostic {
+                    severity: CgrcConfSeverity::CgrcSeverityWarning,
+                    error: CgrcConfError::in_line(CgrcConfErrorKind::CgrcErrorNoColors, regexp_line, regexp_column, regexp_width)
+                        .at(source, regexp_number)
+                });
+            }
+            return;
+        }
+
+        // Only the whole match is colored when the text is replaced.
+        if item.replace.is_some() {
+            return;
+        }
+
+        if colors > captures {
+            if let Some((colours_number, colours_line)) = rule_lines.colours {
+                let colours_column = "colours=".len();
+                diagnostics.push(CgrcConfDiagnostic {
+                    severity: CgrcConfSeverity::CgrcSeverityWarning,
+                    error: CgrcConfError::in_line(
+                        CgrcConfErrorKind::CgrcErrorTooManyColors(colors, captures),
+                        colours_line,
+                        colours_column,
+                        colours_line.chars().count() - colours_column
+                    ).at(source, colours_number)
+                });
+            }
+        }
+        else if colors < captures {
+            diagnostics.push(CgrcConfDiagnostic {
+                severity: CgrcConfSeverity::CgrcSeverityWarning,
+                error: CgrcConfError::in_line(
+                    CgrcConfErrorKind::CgrcErrorUncoloredCaptures(colors, captures),
+                    regexp_line,
+                    regexp_column,
+                    regexp_width
+                ).at(source, regexp_number)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(conf: &str) -> Vec<(CgrcConfSeverity, String, Option<usize>, usize)> {
+        CGRCChecker::check_conf_string(conf, "test").into_iter()
+            .map(|d| (d.severity, d.error.kind.to_string(), d.error.line_number, d.error.column))
+            .collect()
+    }
+
+    #[test]
+    fn valid_rules_have_no_diagnostics() {
+        assert!(check("# comment\nregexp=(a)(b)\ncolours=default,red,blue\n-\nregexp=c\nskip=yes\n").is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_warnings() {
+        assert_eq!(check("color=red\nregexp=a\ncolours=red\n"), vec![
+            (CgrcConfSeverity::CgrcSeverityWarning, String::from("unknown key \"color\""), Some(1), 0)
+        ]);
+    }
+
+    #[test]
+    fn unknown_colors_are_errors() {
+        assert_eq!(check("regexp=a\ncolours=bold reed\n"), vec![
+            (CgrcConfSeverity::CgrcSeverityError, String::from("unknown colour \"reed\""), Some(2), 13)
+        ]);
+    }
+
+    #[test]
+    fn too_many_colors_are_warnings() {
+        assert_eq!(check("regexp=(a)\ncolours=red,green,blue\n"), vec![
+            (CgrcConfSeverity::CgrcSeverityWarning, String::from("3 colours but the regex only has 1 capture groups"), Some(2), 8)
+        ]);
+    }
+
+    #[test]
+    fn uncolored_captures_are_warnings() {
+        assert_eq!(check("regexp=(a)(b)(c)\ncolours=red,green\n"), vec![
+            (CgrcConfSeverity::CgrcSeverityWarning, String::from("capture groups from 2 to 3 have no colour"), Some(1), 7)
+        ]);
+        assert_eq!(check("regexp=(a)\ncolours=red\n"), vec![
+            (CgrcConfSeverity::CgrcSeverityWarning, String::from("capture group 1 has no colour"), Some(1), 7)
+        ]);
+    }
+
+    #[test]
+    fn rules_without_colors_are_warnings() {
+        assert_eq!(check("regexp=a\ncount=once\n"), vec![
+            (CgrcConfSeverity::CgrcSeverityWarning, String::from("rule has no colours"), Some(1), 7)
+        ]);
+        assert!(check("regexp=a\ncolours=red\n-\nregexp=b\ncount=previous\n").is_empty());
+    }
+}
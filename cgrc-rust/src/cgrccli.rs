@@ -16,11 +16,17 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
- use clap::Parser;
+ use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use cgrc::cgrcdata::{CgrcAnsiPolicy, CgrcColorDepth, CgrcColorMode, CgrcHtmlTheme, CgrcOutputFormat, CgrcSanitizeMode};
+use cgrc::cgrcinput::encoding_for_label;
+use encoding_rs::Encoding;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub action: Option<CliAction>,
     #[arg(long = "list-locations")]
     pub list_locations: bool,
     #[arg(long = "location-user")]
@@ -33,5 +39,47 @@ pub struct Cli {
     pub conf_path: bool,
     #[arg(long = "debug")]
     pub debug: bool,
-    pub conf: Option<String>
+    #[arg(long = "color", value_name = "auto|always|never", default_value = "auto")]
+    pub color: CgrcColorMode,
+    #[arg(long = "color-depth", value_name = "16|256|truecolor")]
+    pub color_depth: Option<CgrcColorDepth>,
+    #[arg(long = "ansi", value_name = "keep|override|strip", default_value = "override")]
+    pub ansi: CgrcAnsiPolicy,
+    #[arg(long = "sanitize", value_name = "off|remove|escape", default_value = "off")]
+    pub sanitize: CgrcSanitizeMode,
+    #[arg(long = "strip-ansi")]
+    pub strip_ansi: bool,
+    #[arg(long = "output-format", value_name = "ansi|html", default_value = "ansi")]
+    pub output_format: CgrcOutputFormat,
+    #[arg(long = "html-page", value_name = "dark|light", num_args = 0..=1, default_missing_value = "dark", require_equals = true)]
+    pub html_page: Option<CgrcHtmlTheme>,
+    #[arg(long = "render-svg", value_name = "FILE")]
+    pub render_svg: Option<PathBuf>,
+    #[arg(long = "svg-theme", value_name = "dark|light", default_value = "dark")]
+    pub svg_theme: CgrcHtmlTheme,
+    #[arg(short = 'e', long = "expression", value_name = "RULE")]
+    pub expressions: Vec<String>,
+    #[arg(long = "highlight", value_name = "WORD:COLOURS")]
+    pub highlights: Vec<String>,
+    #[arg(long = "stop-per-conf")]
+    pub stop_per_conf: bool,
+    #[arg(short = 'j', long = "jobs", value_name = "N")]
+    pub jobs: Option<usize>,
+    #[arg(long = "input-encoding", value_name = "ENCODING", value_parser = encoding_for_label)]
+    pub input_encoding: Option<&'static Encoding>,
+    pub confs: Vec<String>,
+    #[arg(last = true)]
+    pub command: Vec<String>
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliAction {
+    /// Checks configurations for errors and suspicious rules
+    Check {
+        #[arg(long = "conf-path")]
+        conf_path: bool,
+        #[arg(long = "all")]
+        all: bool,
+        confs: Vec<String>
+    }
 }
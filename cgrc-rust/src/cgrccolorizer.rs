@@ -0,0 +1,352 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use crate::cgrcconfmanager::CGRCConfManager;
+use crate::cgrcdata::{CgrcAnsiLine, CgrcAnsiPolicy, CgrcColorDepth, CgrcConf, CgrcCountMode, CgrcParserState, CgrcRegexSet, CgrcSanitizeMode, CgrcStyledLine};
+use crate::cgrcerror::{CgrcConfError, CgrcConfErrorKind};
+use crate::cgrcparser::CGRCParser;
+use crate::cgrcrenderer::{CgrcAnsiRenderer, CgrcRenderer};
+
+///
+/// Colorizes text line by line with the rules of a configuration. The
+/// colorizer keeps the state carried from one line to the next, like the
+/// colour of an open block, so each stream needs its own colorizer.
+///
+#[derive(Clone)]
+pub struct Colorizer {
+    conf: CgrcConf,
+    prefilter: Option<CgrcRegexSet>,
+    state: CgrcParserState,
+    depth: CgrcColorDepth,
+    ansi: CgrcAnsiPolicy,
+    sanitize: CgrcSanitizeMode,
+    renderer: Arc<dyn CgrcRenderer>,
+    colorize: bool,
+    debug: bool,
+}
+
+impl Colorizer {
+    ///
+    /// Creates a colorizer applying the rules of the configuration.
+    ///
+    pub fn new(conf: CgrcConf) -> Colorizer {
+        Colorizer {
+            prefilter: CgrcRegexSet::new(&conf.items),
+            conf,
+            state: CgrcParserState::new(),
+            depth: CgrcColorDepth::CgrcDepthTrueColor,
+            ansi: CgrcAnsiPolicy::default(),
+            sanitize: CgrcSanitizeMode::default(),
+            renderer: Arc::new(CgrcAnsiRenderer {}),
+            colorize: true,
+            debug: false,
+        }
+    }
+
+    ///
+    /// Creates a colorizer from a configuration looked up by name, in the
+    /// embedded configurations first, then in the user and system locations.
+    ///
+    pub fn load(name: &str) -> Result<Colorizer, CgrcConfError> {
+        let conf = match CGRCConfManager::load_conf(&name.to_string(), false) {
+            None => return Err(CgrcConfError::new(CgrcConfErrorKind::CgrcErrorIo(io::ErrorKind::NotFound.into()), name)),
+            Some(v) => v
+        };
+
+        Ok(Self::new(CGRCParser::parse_conf_string(conf, name)?))
+    }
+
+    ///
+    /// Returns the configuration used by the colorizer.
+    ///
+    pub fn conf(&self) -> &CgrcConf {
+        &self.conf
+    }
+
+    ///
+    /// Adapts the colours to the depth supported by the output.
+    ///
+    pub fn set_color_depth(&mut self, depth: CgrcColorDepth) {
+        self.depth = depth;
+        self.conf.set_color_depth(depth);
+    }
+
+    ///
+    /// Sets how the colors already present in the input are merged with
+    /// the ones of the rules.
+    ///
+    pub fn set_ansi_policy(&mut self, ansi: CgrcAnsiPolicy) {
+        self.ansi = ansi;
+    }
+
+    ///
+    /// Sets whether the control characters and the escape sequences of the
+    /// input that are not colors are written, removed or escaped.
+    ///
+    pub fn set_sanitize(&mut self, sanitize: CgrcSanitizeMode) {
+        self.sanitize = sanitize;
+    }
+
+    ///
+    /// Sets the renderer writing the formatted lines, e.g. to produce HTML
+    /// instead of escape sequences.
+    ///
+    pub fn set_renderer(&mut self, renderer: Arc<dyn CgrcRenderer>) {
+        self.renderer = renderer;
+    }
+
+    ///
+    /// When colorize is false, lines are only filtered and rewritten by the
+    /// skip and replace rules, with no escape sequences.
+    ///
+    pub fn set_colorize(&mut self, colorize: bool) {
+        self.colorize = colorize;
+    }
+
+    ///
+    /// Logs how the rules are applied to each line.
+    ///
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    ///
+    /// Formats a line, without the line terminator. Returns None when the
+    /// line must not be printed. The rules are applied to the visible text,
+    /// ignoring the escape sequences in the line.
+    ///
+    pub fn colorize_line(&mut self, line: &str) -> Option<String> {
+        let input = CGRCParser::parse_ansi_line(line, self.depth, self.sanitize, &mut self.state);
+        CGRCParser::process_log_line(
+            &self.conf.items,
+            self.prefilter.as_ref(),
+            &mut self.state,
+            input,
+            self.ansi,
+            self.renderer.as_ref(),
+            self.debug,
+            self.colorize
+        )
+    }
+
+    ///
+    /// Formats a line like colorize_line(), returning the text and the
+    /// colors of its segments instead of rendering them. Adjacent segments
+    /// looking the same are merged.
+    ///
+    pub fn style_line(&mut self, line: &str) -> Option<CgrcStyledLine> {
+        let input = CGRCParser::parse_ansi_line(line, self.depth, self.sanitize, &mut self.state);
+        CGRCParser::style_log_line(
+            &self.conf.items,
+            self.prefilter.as_ref(),
+            &mut self.state,
+            input,
+            self.ansi,
+            self.debug,
+            self.colorize,
+            |text, segments, _| {
+                let mut styled = CgrcStyledLine { text, segments: vec![] };
+                for (range, color) in segments {
+                    let color = color.filter(|c| !c.is_plain());
+                    if let Some((last_range, last_color)) = styled.segments.last_mut() {
+                        let same = match (&color, last_color) {
+                            (None, None) => true,
+                            (Some(color), Some(last_color)) => color.transition_seq(Some(last_color)).is_empty(),
+                            _ => false
+                        };
+                        if same {
+                            last_range.end = range.end;
+                            continue;
+                        }
+                    }
+                    styled.segments.push((range, color.map(|c| c.into_owned())));
+                }
+                styled
+            }
+        )
+    }
+
+    ///
+    /// Formats a batch of lines, splitting it among up to jobs threads
+    /// sharing the rules. The results are in the order of the lines. The
+    /// colors of the input are read sequentially first, as they carry on
+    /// from a line to the next. When the rules carry state as well, the
+    /// lines are formatted sequentially.
+    ///
+    pub fn colorize_lines<S: AsRef<str> + Sync>(&mut self, lines: &[S], jobs: usize) -> Vec<Option<String>> {
+        if jobs <= 1 || lines.len() < 2 || self.is_stateful() {
+            return lines.iter()
+                .map(|line| self.colorize_line(line.as_ref()))
+                .collect();
+        }
+
+        let mut inputs: Vec<CgrcAnsiLine> = lines.iter()
+            .map(|line| CGRCParser::parse_ansi_line(line.as_ref(), self.depth, self.sanitize, &mut self.state))
+            .collect();
+        let chunk_size = inputs.len().div_ceil(jobs);
+        let items = &self.conf.items;
+        let prefilter = self.prefilter.as_ref();
+        let ansi = self.ansi;
+        let renderer = self.renderer.as_ref();
+        let (debug, colorize) = (self.debug, self.colorize);
+        thread::scope(|scope| {
+            let workers: Vec<_> = inputs.chunks_mut(chunk_size)
+                .map(|chunk| scope.spawn(move || {
+                    let mut state = CgrcParserState::new();
+                    chunk.iter_mut()
+                        .map(|input| {
+                            let input = std::mem::take(input);
+                            CGRCParser::process_log_line(items, prefilter, &mut state, input, ansi, renderer, debug, colorize)
+                        })
+                        .collect::<Vec<Option<String>>>()
+                }))
+                .collect();
+            workers.into_iter()
+                .flat_map(|worker| match worker.join() {
+                    Err(e) => std::panic::resume_unwind(e),
+                    Ok(v) => v
+                })
+                .collect()
+        })
+    }
+
+    ///
+    /// Returns true when the rules carry state from a line to the next,
+    /// i.e. when block or unblock rules are used.
+    ///
+    pub fn is_stateful(&self) -> bool {
+        let mut mode: Option<CgrcCountMode> = None;
+        for item in &self.conf.items {
+            if item.count_mode != Some(CgrcCountMode::CgrcCountPrevious) {
+                mode = item.count_mode.clone();
+            }
+            if matches!(mode, Some(CgrcCountMode::CgrcCountBlock) | Some(CgrcCountMode::CgrcCountUnblock)) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    ///
+    /// Forgets the state carried from the previous lines.
+    ///
+    pub fn reset(&mut self) {
+        self.state = CgrcParserState::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn colorizer(rules: &str) -> Colorizer {
+        Colorizer::new(CGRCParser::parse_conf_string(rules.to_string(), "test").unwrap())
+    }
+
+    fn colorize_sequentially(rules: &str, lines: &[String]) -> Vec<Option<String>> {
+        let mut colorizer = colorizer(rules);
+        lines.iter().map(|line| colorizer.colorize_line(line)).collect()
+    }
+
+    #[test]
+    fn parallel_lines_match_the_sequential_ones_in_order() {
+        let rules = "regexp=\\d+\ncolours=red\n-\nregexp=skip me\nskip=yes\n-\nregexp=(a)(b)\ncolours=default,green,blue\n";
+        let lines: Vec<String> = (0..97)
+            .map(|i| match i % 3 {
+                0 => format!("line {} ab", i),
+                1 => format!("skip me {}", i),
+                _ => format!("{} plain", i)
+            })
+            .collect();
+        let expected = colorize_sequentially(rules, &lines);
+        assert_eq!(expected[1], None);
+        assert!(expected[0].as_ref().unwrap().contains("\x1b[32ma"));
+        for jobs in [2, 3, 8, 200] {
+            assert_eq!(colorizer(rules).colorize_lines(&lines, jobs), expected);
+        }
+    }
+
+    #[test]
+    fn input_colors_carry_across_chunks() {
+        let rules = "regexp=zzz\ncolours=red\n";
+        let lines: Vec<String> = vec!["a\x1b[32m", "b", "c", "d", "e\x1b[0m", "f", "g", "h"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let expected = colorize_sequentially(rules, &lines);
+        assert_eq!(expected[3], Some(String::from("\x1b[32md\x1b[0m")));
+        assert_eq!(colorizer(rules).colorize_lines(&lines, 4), expected);
+    }
+
+    #[test]
+    fn block_rules_are_stateful() {
+        assert!(!colorizer("regexp=a\ncolours=red\ncount=stop\n").is_stateful());
+        assert!(colorizer("regexp=a\ncolours=red\ncount=block\n").is_stateful());
+        assert!(colorizer("regexp=a\ncolours=red\n-\nregexp=b\ncolours=red\ncount=unblock\n").is_stateful());
+        assert!(colorizer("regexp=a\ncolours=red\ncount=block\n-\nregexp=b\ncolours=red\ncount=previous\n").is_stateful());
+        assert!(!colorizer("regexp=a\ncolours=red\ncount=more\n-\nregexp=b\ncolours=red\ncount=previous\n").is_stateful());
+    }
+
+    #[test]
+    fn stateful_rules_are_applied_sequentially() {
+        let rules = "regexp=BEGIN\ncolours=blue\ncount=block\n-\nregexp=END\ncolours=red\ncount=unblock\n";
+        let lines: Vec<String> = vec!["BEGIN", "a", "b", "c", "d", "END", "e", "f"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let expected = colorize_sequentially(rules, &lines);
+        assert_eq!(expected[4], Some(String::from("\x1b[34md\x1b[0m")));
+        assert_eq!(colorizer(rules).colorize_lines(&lines, 4), expected);
+    }
+
+    fn layered(stop_per_conf: bool, inline: Option<&str>) -> Colorizer {
+        let mut conf = CgrcConf::new();
+        for rules in ["regexp=a\ncolours=red\ncount=stop\n", "regexp=b\ncolours=blue\n"] {
+            conf.append(CGRCParser::parse_conf_string(rules.to_string(), "test").unwrap(), stop_per_conf);
+        }
+        if let Some(rule) = inline {
+            conf.append(CGRCParser::parse_inline_rule(rule, "-e").unwrap(), true);
+        }
+        Colorizer::new(conf)
+    }
+
+    #[test]
+    fn stop_rules_stop_the_following_confs() {
+        let mut colorizer = layered(false, None);
+        assert_eq!(colorizer.colorize_line("ab").unwrap(), "\x1b[31ma\x1b[0mb");
+        assert_eq!(colorizer.conf().items.iter().map(|i| i.layer).collect::<Vec<usize>>(), vec![0, 0]);
+    }
+
+    #[test]
+    fn stop_rules_stop_only_their_conf_when_stacked() {
+        let mut colorizer = layered(true, None);
+        assert_eq!(colorizer.colorize_line("ab").unwrap(), "\x1b[31ma\x1b[34mb\x1b[0m");
+        assert_eq!(colorizer.conf().items.iter().map(|i| i.layer).collect::<Vec<usize>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn inline_rules_are_always_stacked() {
+        let mut colorizer = layered(false, Some("regexp=c;colours=green"));
+        assert_eq!(colorizer.colorize_line("abc").unwrap(), "\x1b[31ma\x1b[0mb\x1b[32mc\x1b[0m");
+        assert_eq!(colorizer.conf().items.iter().map(|i| i.layer).collect::<Vec<usize>>(), vec![0, 0, 1]);
+    }
+}
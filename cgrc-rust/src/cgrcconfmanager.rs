@@ -20,7 +20,9 @@ use directories::ProjectDirs;
 use std::fs;
 use std::str;
 use std::path::{Path, PathBuf};
-use crate::cgrcconfstorage::load_confs;
+use crate::cgrcconfstorage::{load_confs, load_conf_map};
+use crate::cgrcdata::CgrcConfMapItem;
+use crate::cgrcerror::{CgrcConfError, CgrcConfErrorKind};
 use crate::cgrcparser::CGRCParser;
 
 pub struct CGRCConfManager {}
@@ -37,24 +39,70 @@ impl CGRCConfManager {
             }
         }
 
-        return None;
+        None
+    }
+
+    pub fn conf_map_file_name() -> &'static str {
+        "cgrc.conf"
+    }
+
+    ///
+    /// Loads the map from command lines to configurations. Entries from the
+    /// user location come first, then system entries and finally the
+    /// embedded ones.
+    /// 
+    pub fn load_conf_map() -> Result<Vec<CgrcConfMapItem>, CgrcConfError> {
+        let mut items: Vec<CgrcConfMapItem> = vec![];
+        let mut paths: Vec<PathBuf> = vec![];
+        if let Some(user_path) = Self::default_user_path() {
+            paths.push(PathBuf::from(user_path).join(Self::conf_map_file_name()));
+        }
+        paths.push(Path::new(Self::default_system_path()).join(Self::conf_map_file_name()));
+        for path in paths {
+            if !path.exists() {
+                continue;
+            }
+            let source = path.display().to_string();
+            match fs::read_to_string(&path) {
+                Ok(v) => items.append(&mut CGRCParser::parse_conf_map_string(&v, &source)?),
+                Err(e) => return Err(CgrcConfError::new(CgrcConfErrorKind::CgrcErrorIo(e), &source))
+            }
+        }
+
+        match str::from_utf8(load_conf_map()) {
+            Ok(v) => items.append(&mut CGRCParser::parse_conf_map_string(v, Self::conf_map_file_name())?),
+            Err(e) => log::error!("Error: {}. Cannot read embedded conf map", e)
+        }
+
+        Ok(items)
+    }
+
+    ///
+    /// Returns the name of the configuration to use for a command line.
+    /// 
+    pub fn conf_for_command(command: &[String]) -> Result<Option<String>, CgrcConfError> {
+        let command_line = command.join(" ");
+        Ok(Self::load_conf_map()?
+            .into_iter()
+            .find(|item| item.regex.is_match(&command_line).unwrap_or(false))
+            .map(|item| item.conf))
     }
 
     pub fn load_conf(conf: &String, local_path: bool) -> Option<String> {
         if local_path {
             let path = Path::new(conf);
-            return if path.exists() {
+            if path.exists() {
                 match fs::read_to_string(path) {
-                    Ok(c) => return Some(c),
+                    Ok(c) => Some(c),
                     Err(e) => {
                         log::error!("Error: {}. Cannot read conf {}", e, conf);
-                        return None;
+                        None
                     }
                 }
             }
             else {
                 None
-            };
+            }
         }
         else {
             let confs = load_confs();
@@ -79,7 +127,7 @@ impl CGRCConfManager {
                 }
             }
 
-            if conf_path == None {
+            if conf_path.is_none() {
                 let proposed_path = Path::new(Self::default_system_path())
                     .join(conf);
                 if proposed_path.as_path().exists() {
@@ -89,7 +137,7 @@ impl CGRCConfManager {
                 }
             }
 
-            return match conf_path {
+            match conf_path {
                 None => None,
                 Some(file_path) => {
                     match fs::read_to_string(Path::new(&file_path)) {
@@ -111,13 +159,33 @@ impl CGRCConfManager {
         println!("Embedded configurations:");
         Self::print_avail_embedded_confs();
 
-        println!("");
+        println!();
         println!("User configurations:");
         Self::print_avail_user_confs();
 
-        println!("");
+        println!();
         println!("System configurations:");
         Self::print_avail_system_confs();
+
+        println!();
+        println!("Command mappings:");
+        Self::print_conf_map();
+    }
+
+    ///
+    /// Prints the map from command lines to configurations to stdout.
+    /// 
+    pub fn print_conf_map() {
+        let conf_map = match Self::load_conf_map() {
+            Err(e) => {
+                eprintln!("{e}");
+                return;
+            },
+            Ok(v) => v
+        };
+        for item in conf_map {
+            println!("\t{} -> {}", item.regex.as_str(), item.conf);
+        }
     }
 
     ///
@@ -133,7 +201,13 @@ impl CGRCConfManager {
                 },
                 Ok(s) => s
             };
-            let conf = CGRCParser::parse_conf_string(conf_string.to_string());
+            let conf = match CGRCParser::parse_conf_string(conf_string.to_string(), key) {
+                Err(e) => {
+                    eprintln!("{e}");
+                    continue;
+                },
+                Ok(v) => v
+            };
             println!("\t{key} -> {}", match conf.description {
                 None => String::from("?"),
                 Some(v) => v
@@ -146,7 +220,7 @@ impl CGRCConfManager {
     /// 
     pub fn print_avail_user_confs() {
         if let Some(v) = Self::default_user_path() {
-            Self::print_avail_confs_in_path(&v.to_string());
+            Self::print_avail_confs_in_path(&v);
         }
     }
 
@@ -154,35 +228,54 @@ impl CGRCConfManager {
     /// Prints system-wide configurations to stdout.
     /// 
     pub fn print_avail_system_confs() {
-        Self::print_avail_confs_in_path(&Self::default_system_path().to_string());
+        Self::print_avail_confs_in_path(Self::default_system_path());
     }
 
     ///
     /// Print available configurations in the path.
     /// 
-    fn print_avail_confs_in_path(path: &String) {
+    fn print_avail_confs_in_path(path: &str) {
+        for path_string in Self::conf_files_in_path(path) {
+            let conf = match CGRCParser::parse_conf(&path_string) {
+                Err(e) => {
+                    eprintln!("{e}");
+                    continue;
+                },
+                Ok(v) => v
+            };
+            println!("\t{} -> {}", path_string, match conf.description {
+                None => String::from("?"),
+                Some(v) => v
+            });
+        }
+    }
+
+    ///
+    /// Returns the paths of the configuration files in the path.
+    /// 
+    pub fn conf_files_in_path(path: &str) -> Vec<String> {
+        let mut files: Vec<String> = vec![];
         let read_dir = match fs::read_dir(path) {
-            Err(_) => return,
+            Err(_) => return files,
             Ok(v) => v
         };
         for file in read_dir {
             let item = match file {
                 Err(e) => {
-                    log::warn!("Failed to list file: {}", e.to_string());
+                    log::warn!("Failed to list file: {}", e);
                     continue;
                 },
                 Ok(f) => f
             };
+            if item.file_name() == Self::conf_map_file_name() {
+                continue;
+            }
             let path = PathBuf::from(path).join(item.file_name());
-            let path_string = match path.to_str() {
-                None => continue,
-                Some(v) => v
-            };
-            let conf = CGRCParser::parse_conf(&path_string.to_string());
-            println!("\t{} -> {}", path_string, match conf.description {
-                None => String::from("?"),
-                Some(v) => v
-            });
+            if let Some(path_string) = path.to_str() {
+                files.push(path_string.to_string());
+            }
         }
+
+        files
     }
 }
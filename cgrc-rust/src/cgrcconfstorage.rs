@@ -25,7 +25,12 @@ pub fn load_confs() -> HashMap<&'static str, &'static [u8]> {
         ("dockerstats", &include_bytes!("../conf/dockerstats")[..]),
         ("logcat", &include_bytes!("../conf/logcat")[..]),
         ("nginx", &include_bytes!("../conf/nginx")[..]),
+        ("patterns", &include_bytes!("../conf/patterns")[..]),
         ("ping", &include_bytes!("../conf/ping")[..]),
         ("prio", &include_bytes!("../conf/prio")[..])
     ])
 }
+
+pub fn load_conf_map() -> &'static [u8] {
+    &include_bytes!("../conf/cgrc.conf")[..]
+}
@@ -16,9 +16,14 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::cmp::Eq;
-use fancy_regex::Regex;
+use std::env;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use fancy_regex::{Expander, Regex};
+use regex::{RegexSet, SetMatches};
 
 ///
 /// Values to set attributes to text.
@@ -69,6 +74,7 @@ pub enum CgrcCountMode {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[allow(clippy::enum_variant_names)]
 enum CgrcColor {
     LcBlack = 0,
     LcRed = 1,
@@ -123,6 +129,377 @@ pub enum LcLogColor {
     LcForgColDefault = 30 + CgrcColor::LcDefault as isize,
 }
 
+///
+/// Number of colors supported by the terminal.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum CgrcColorDepth {
+    CgrcDepth16,
+    CgrcDepth256,
+    CgrcDepthTrueColor,
+}
+
+impl CgrcColorDepth {
+    ///
+    /// Detects the color depth of the terminal from COLORTERM and TERM.
+    ///
+    pub fn detect() -> CgrcColorDepth {
+        let colorterm = env::var("COLORTERM").unwrap_or_default().to_lowercase();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return CgrcColorDepth::CgrcDepthTrueColor;
+        }
+
+        let term = env::var("TERM").unwrap_or_default().to_lowercase();
+        if term.ends_with("-direct") || term.contains("truecolor") {
+            return CgrcColorDepth::CgrcDepthTrueColor;
+        }
+        if term.contains("256color") {
+            return CgrcColorDepth::CgrcDepth256;
+        }
+
+        CgrcColorDepth::CgrcDepth16
+    }
+}
+
+impl FromStr for CgrcColorDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "16" => Ok(CgrcColorDepth::CgrcDepth16),
+            "256" => Ok(CgrcColorDepth::CgrcDepth256),
+            "truecolor" | "24bit" => Ok(CgrcColorDepth::CgrcDepthTrueColor),
+            _ => Err(format!("invalid color depth \"{}\", expected 16, 256 or truecolor", s))
+        }
+    }
+}
+
+///
+/// When to color the output.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum CgrcColorMode {
+    CgrcColorAuto,
+    CgrcColorAlways,
+    CgrcColorNever,
+}
+
+impl CgrcColorMode {
+    ///
+    /// Returns true if the output should be colored. In auto mode, NO_COLOR
+    /// disables the colors and CLICOLOR_FORCE forces them, otherwise colors
+    /// are used only when writing to a terminal.
+    ///
+    pub fn should_colorize(&self, is_terminal: bool) -> bool {
+        match self {
+            CgrcColorMode::CgrcColorAlways => true,
+            CgrcColorMode::CgrcColorNever => false,
+            CgrcColorMode::CgrcColorAuto => {
+                if env::var("NO_COLOR").is_ok_and(|v| !v.is_empty()) {
+                    return false;
+                }
+                if env::var("CLICOLOR_FORCE").is_ok_and(|v| !v.is_empty() && v != "0") {
+                    return true;
+                }
+                is_terminal
+            }
+        }
+    }
+}
+
+impl FromStr for CgrcColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(CgrcColorMode::CgrcColorAuto),
+            "always" => Ok(CgrcColorMode::CgrcColorAlways),
+            "never" => Ok(CgrcColorMode::CgrcColorNever),
+            _ => Err(format!("invalid color mode \"{}\", expected auto, always or never", s))
+        }
+    }
+}
+
+///
+/// What to do with the colours already present in the input.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum CgrcAnsiPolicy {
+    /// The colours of the input are drawn on top of the ones of the rules.
+    CgrcAnsiKeep,
+    /// The colours of the rules are drawn on top of the ones of the input.
+    #[default]
+    CgrcAnsiOverride,
+    /// The colours of the input are removed.
+    CgrcAnsiStrip,
+}
+
+impl FromStr for CgrcAnsiPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(CgrcAnsiPolicy::CgrcAnsiKeep),
+            "override" => Ok(CgrcAnsiPolicy::CgrcAnsiOverride),
+            "strip" => Ok(CgrcAnsiPolicy::CgrcAnsiStrip),
+            _ => Err(format!("invalid ansi policy \"{}\", expected keep, override or strip", s))
+        }
+    }
+}
+
+///
+/// What to do with the control characters and the escape sequences of the
+/// input that are not colors, like those moving the cursor or setting the
+/// title of the terminal.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum CgrcSanitizeMode {
+    /// Written to the output unchanged.
+    #[default]
+    CgrcSanitizeOff,
+    /// Removed from the output.
+    CgrcSanitizeRemove,
+    /// Written in caret notation, e.g. ^[ for the escape character.
+    CgrcSanitizeEscape,
+}
+
+impl FromStr for CgrcSanitizeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(CgrcSanitizeMode::CgrcSanitizeOff),
+            "remove" => Ok(CgrcSanitizeMode::CgrcSanitizeRemove),
+            "escape" => Ok(CgrcSanitizeMode::CgrcSanitizeEscape),
+            _ => Err(format!("invalid sanitize mode \"{}\", expected off, remove or escape", s))
+        }
+    }
+}
+
+///
+/// Format of the formatted lines.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum CgrcOutputFormat {
+    #[default]
+    CgrcOutputAnsi,
+    CgrcOutputHtml,
+}
+
+impl FromStr for CgrcOutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ansi" => Ok(CgrcOutputFormat::CgrcOutputAnsi),
+            "html" => Ok(CgrcOutputFormat::CgrcOutputHtml),
+            _ => Err(format!("invalid output format \"{}\", expected ansi or html", s))
+        }
+    }
+}
+
+///
+/// Theme of the pages written by the HTML renderer.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum CgrcHtmlTheme {
+    #[default]
+    CgrcHtmlDark,
+    CgrcHtmlLight,
+}
+
+impl CgrcHtmlTheme {
+    ///
+    /// Returns the default foreground and background colors of the theme.
+    ///
+    pub fn colors(&self) -> (&'static str, &'static str) {
+        match self {
+            CgrcHtmlTheme::CgrcHtmlDark => ("#d0d0d0", "#1c1c1c"),
+            CgrcHtmlTheme::CgrcHtmlLight => ("#1c1c1c", "#ffffff")
+        }
+    }
+}
+
+impl FromStr for CgrcHtmlTheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dark" => Ok(CgrcHtmlTheme::CgrcHtmlDark),
+            "light" => Ok(CgrcHtmlTheme::CgrcHtmlLight),
+            _ => Err(format!("invalid theme \"{}\", expected dark or light", s))
+        }
+    }
+}
+
+///
+/// RGB values of the 16 ANSI colors, as in the xterm defaults.
+///
+const ANSI16_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+///
+/// Levels of each component in the 6x6x6 cube of the 256 colors palette.
+///
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+///
+/// Color outside the 16 ANSI colors: an index in the xterm 256 colors
+/// palette or a 24-bit RGB value.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum CgrcExtColor {
+    CgrcExtIndexed(u8),
+    CgrcExtRgb(u8, u8, u8),
+}
+
+impl CgrcExtColor {
+    ///
+    /// Parses color208 or #ff8800.
+    ///
+    pub fn parse(token: &str) -> Option<CgrcExtColor> {
+        if let Some(index) = token.strip_prefix("color") {
+            return index.parse::<u8>().ok().map(CgrcExtColor::CgrcExtIndexed);
+        }
+        if let Some(hex) = token.strip_prefix('#') {
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            return Some(CgrcExtColor::CgrcExtRgb(component(0)?, component(2)?, component(4)?));
+        }
+
+        None
+    }
+
+    ///
+    /// Returns the SGR parameters for the color, downsampled to the depth
+    /// if needed. Base is 38 for the foreground and 48 for the background.
+    ///
+    pub fn sgr(&self, base: u8, depth: CgrcColorDepth) -> String {
+        match (depth, self) {
+            (CgrcColorDepth::CgrcDepthTrueColor, CgrcExtColor::CgrcExtRgb(r, g, b)) => format!("{};2;{};{};{}", base, r, g, b),
+            (CgrcColorDepth::CgrcDepth16, _) => {
+                // Maps to 30-37 and 90-97 for the foreground, 40-47 and
+                // 100-107 for the background.
+                let index = self.to_ansi16();
+                if index < 8 {
+                    (base - 8 + index).to_string()
+                }
+                else {
+                    (base + 52 + index - 8).to_string()
+                }
+            },
+            _ => format!("{};5;{}", base, self.to_ansi256()),
+        }
+    }
+
+    ///
+    /// Returns the RGB value of the color.
+    ///
+    pub fn to_rgb(&self) -> (u8, u8, u8) {
+        match *self {
+            CgrcExtColor::CgrcExtRgb(r, g, b) => (r, g, b),
+            CgrcExtColor::CgrcExtIndexed(index) if index < 16 => ANSI16_RGB[index as usize],
+            CgrcExtColor::CgrcExtIndexed(index) if index < 232 => {
+                let index = index - 16;
+                (
+                    CUBE_LEVELS[(index / 36) as usize],
+                    CUBE_LEVELS[((index / 6) % 6) as usize],
+                    CUBE_LEVELS[(index % 6) as usize]
+                )
+            },
+            CgrcExtColor::CgrcExtIndexed(index) => {
+                let level = 8 + 10 * (index - 232);
+                (level, level, level)
+            }
+        }
+    }
+
+    ///
+    /// Returns the nearest index in the 256 colors palette.
+    ///
+    pub fn to_ansi256(&self) -> u8 {
+        let (r, g, b) = match *self {
+            CgrcExtColor::CgrcExtIndexed(index) => return index,
+            CgrcExtColor::CgrcExtRgb(r, g, b) => (r, g, b)
+        };
+
+        let cube_index = |c: u8| {
+            CUBE_LEVELS.iter()
+                .enumerate()
+                .min_by_key(|(_, level)| (**level as i32 - c as i32).abs())
+                .map(|(i, _)| i as u8)
+                .unwrap()
+        };
+        let cube = 16 + 36 * cube_index(r) + 6 * cube_index(g) + cube_index(b);
+
+        let average = (r as u32 + g as u32 + b as u32) / 3;
+        let gray = 232 + ((average.saturating_sub(3) / 10).min(23)) as u8;
+
+        let target = (r, g, b);
+        if Self::distance(CgrcExtColor::CgrcExtIndexed(gray).to_rgb(), target) < Self::distance(CgrcExtColor::CgrcExtIndexed(cube).to_rgb(), target) {
+            gray
+        }
+        else {
+            cube
+        }
+    }
+
+    ///
+    /// Returns the nearest of the 16 ANSI colors, as an index from 0 to 15.
+    ///
+    pub fn to_ansi16(&self) -> u8 {
+        if let CgrcExtColor::CgrcExtIndexed(index) = *self {
+            if index < 16 {
+                return index;
+            }
+        }
+
+        let target = self.to_rgb();
+        ANSI16_RGB.iter()
+            .enumerate()
+            .min_by_key(|(_, rgb)| Self::distance(**rgb, target))
+            .map(|(i, _)| i as u8)
+            .unwrap()
+    }
+
+    fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+        let dr = a.0 as i32 - b.0 as i32;
+        let dg = a.1 as i32 - b.1 as i32;
+        let db = a.2 as i32 - b.2 as i32;
+        (dr * dr + dg * dg + db * db) as u32
+    }
+}
+
+pub static CONF_KEYS: &[&str] = &[
+    "desc",
+    "regexp",
+    "replace",
+    "colours",
+    "skip",
+    "count",
+    "define",
+    "include",
+    "extends",
+];
+
 pub static COLORS_ATTRS: phf::Map<&'static str, CgrcAttrib> = phf::phf_map! {
     "none" => CgrcAttrib::CgrcNone,
     "unchanged" => CgrcAttrib::CgrcNone,
@@ -139,7 +516,7 @@ pub static COLORS_ATTRS: phf::Map<&'static str, CgrcAttrib> = phf::phf_map! {
 };
 
 pub fn colors_attr_clear(attr: &CgrcAttrib) -> CgrcResetAttrib {
-    return match attr {
+    match attr {
         CgrcAttrib::CgrcBright => CgrcResetAttrib::CgrcResetBright,
         CgrcAttrib::CgrcNone => CgrcResetAttrib::CgrcResetNone,
         CgrcAttrib::CgrcReset => CgrcResetAttrib::CgrcResetNone,
@@ -200,8 +577,11 @@ pub struct CgrcColorItem {
     pub attrs: HashSet<CgrcAttrib>,
     pub forg: LcLogColor,
     pub back: LcBackColor,
+    pub forg_ext: Option<CgrcExtColor>,
+    pub back_ext: Option<CgrcExtColor>,
     pub escape_seq: String,
     pub clear_seq: String,
+    pub depth: CgrcColorDepth,
 }
 
 impl CgrcColorItem {
@@ -209,25 +589,252 @@ impl CgrcColorItem {
     /// Creates a new color item for a line.
     ///
     pub fn new(attrs: HashSet<CgrcAttrib>, forg: LcLogColor, back: LcBackColor) -> CgrcColorItem {
-        let escape_seq = CgrcColorItem::build_escape_seq(&attrs, &forg, &back);
+        CgrcColorItem::new_ext(attrs, forg, back, None, None)
+    }
+
+    ///
+    /// Creates a new color item for a line, with 256 colors or RGB values.
+    /// When set, the extended colors take precedence over forg and back.
+    ///
+    pub fn new_ext(attrs: HashSet<CgrcAttrib>,
+                   forg: LcLogColor,
+                   back: LcBackColor,
+                   forg_ext: Option<CgrcExtColor>,
+                   back_ext: Option<CgrcExtColor>) -> CgrcColorItem {
+        let escape_seq = CgrcColorItem::build_escape_seq(&attrs, &forg, &back, &forg_ext, &back_ext, CgrcColorDepth::CgrcDepthTrueColor);
         let clear_seq = CgrcColorItem::build_clear_seq(&attrs);
         CgrcColorItem {
             attrs,
             forg,
             back,
+            forg_ext,
+            back_ext,
             escape_seq,
             clear_seq,
+            depth: CgrcColorDepth::CgrcDepthTrueColor,
+        }
+    }
+
+    ///
+    /// Rebuilds the escape sequence, downsampling the extended colors to
+    /// the depth supported by the terminal.
+    ///
+    pub fn set_color_depth(&mut self, depth: CgrcColorDepth) {
+        self.depth = depth;
+        self.escape_seq = CgrcColorItem::build_escape_seq(
+            &self.attrs,
+            &self.forg,
+            &self.back,
+            &self.forg_ext,
+            &self.back_ext,
+            depth
+        );
+    }
+
+    ///
+    /// Returns true when the item leaves the text with the default colors
+    /// and no attributes, like default.
+    ///
+    pub fn is_plain(&self) -> bool {
+        self.forg == LcLogColor::LcForgColDefault
+            && self.back == LcBackColor::LcBackColDefault
+            && self.forg_ext.is_none()
+            && self.back_ext.is_none()
+            && self.text_attrs().is_empty()
+    }
+
+    ///
+    /// Builds the shortest escape sequence changing the text from the
+    /// previous color, or from the default colors when there is none, to
+    /// this one. Removing an attribute requires resetting everything, as
+    /// some attributes share the same reset code.
+    ///
+    pub fn transition_seq(&self, from: Option<&CgrcColorItem>) -> String {
+        let default_forg = (LcLogColor::LcForgColDefault as u8).to_string();
+        let default_back = (LcBackColor::LcBackColDefault as u8).to_string();
+        let (mut from_forg, mut from_back, mut from_attrs) = match from {
+            None => (default_forg.clone(), default_back.clone(), vec![]),
+            Some(c) => (c.forg_param(), c.back_param(), c.text_attrs())
+        };
+
+        let attrs = self.text_attrs();
+        let mut params: Vec<String> = vec![];
+        if from_attrs.iter().any(|a| !attrs.contains(a)) {
+            params.push((CgrcAttrib::CgrcReset as u8).to_string());
+            from_forg = default_forg;
+            from_back = default_back;
+            from_attrs = vec![];
+        }
+
+        let forg = self.forg_param();
+        if forg != from_forg {
+            params.push(forg);
+        }
+        let back = self.back_param();
+        if back != from_back {
+            params.push(back);
+        }
+        for attr in attrs.iter().filter(|a| !from_attrs.contains(a)) {
+            params.push((*attr as u8).to_string());
+        }
+
+        match params.is_empty() {
+            true => String::new(),
+            false => format!("{}[{}m", 0x1b as char, params.join(";"))
+        }
+    }
+
+    ///
+    /// Composes the color with another one applied on top of it. The colors
+    /// set by the upper item replace these ones, while the attributes are
+    /// merged, so bold and red give bold red. An upper item resetting the
+    /// attributes, like default, replaces this one entirely.
+    ///
+    pub fn compose(&self, upper: &CgrcColorItem) -> CgrcColorItem {
+        if upper.attrs.contains(&CgrcAttrib::CgrcReset) {
+            return upper.clone();
+        }
+
+        let attrs: HashSet<CgrcAttrib> = self.attrs.iter()
+            .filter(|a| **a != CgrcAttrib::CgrcReset)
+            .chain(upper.attrs.iter())
+            .copied()
+            .collect();
+        let (forg, forg_ext) = match upper.forg != LcLogColor::LcForgColDefault || upper.forg_ext.is_some() {
+            true => (upper.forg, upper.forg_ext),
+            false => (self.forg, self.forg_ext)
+        };
+        let (back, back_ext) = match upper.back != LcBackColor::LcBackColDefault || upper.back_ext.is_some() {
+            true => (upper.back, upper.back_ext),
+            false => (self.back, self.back_ext)
+        };
+        let mut item = CgrcColorItem::new_ext(attrs, forg, back, forg_ext, back_ext);
+        item.set_color_depth(self.depth);
+        item
+    }
+
+    ///
+    /// Returns the color resulting from applying the parameters of an SGR
+    /// sequence, e.g. "1;31" for \x1b[1;31m, to this one. Unsupported
+    /// parameters are ignored.
+    ///
+    pub fn apply_sgr(&self, params: &str) -> CgrcColorItem {
+        let mut attrs = self.attrs.clone();
+        let (mut forg, mut back) = (self.forg, self.back);
+        let (mut forg_ext, mut back_ext) = (self.forg_ext, self.back_ext);
+        let mut params = params.split([';', ':']).map(|p| p.parse::<u16>().unwrap_or(0));
+        while let Some(param) = params.next() {
+            match param {
+                0 => {
+                    attrs.clear();
+                    (forg, back, forg_ext, back_ext) = (LcLogColor::LcForgColDefault, LcBackColor::LcBackColDefault, None, None);
+                },
+                1..=9 => {
+                    if let Some(attr) = COLORS_ATTRS.values().find(|a| **a as u16 == param) {
+                        attrs.insert(*attr);
+                    }
+                },
+                21 | 22 => {
+                    attrs.remove(&CgrcAttrib::CgrcBright);
+                    attrs.remove(&CgrcAttrib::CgrcDim);
+                },
+                23 => { attrs.remove(&CgrcAttrib::CgrcItalic); },
+                24 => { attrs.remove(&CgrcAttrib::CgrcUnderline); },
+                25 => {
+                    attrs.remove(&CgrcAttrib::CgrcBlink);
+                    attrs.remove(&CgrcAttrib::CgrcRapidBlink);
+                },
+                27 => { attrs.remove(&CgrcAttrib::CgrcReverse); },
+                28 => { attrs.remove(&CgrcAttrib::CgrcHidden); },
+                29 => { attrs.remove(&CgrcAttrib::CgrcStrikethrough); },
+                30..=37 | 39 | 90..=97 => {
+                    if let Some(color) = COLORS_FORG.values().find(|c| **c as u16 == param) {
+                        forg = *color;
+                    }
+                    else {
+                        forg = LcLogColor::LcForgColDefault;
+                    }
+                    forg_ext = None;
+                },
+                40..=47 | 49 | 100..=107 => {
+                    if let Some(color) = COLORS_BACK.values().find(|c| **c as u16 == param) {
+                        back = *color;
+                    }
+                    else {
+                        back = LcBackColor::LcBackColDefault;
+                    }
+                    back_ext = None;
+                },
+                38 | 48 => {
+                    let color = match params.next() {
+                        Some(5) => params.next().map(|i| CgrcExtColor::CgrcExtIndexed(i as u8)),
+                        Some(2) => {
+                            let mut component = || params.next().unwrap_or(0) as u8;
+                            Some(CgrcExtColor::CgrcExtRgb(component(), component(), component()))
+                        },
+                        _ => None
+                    };
+                    if param == 38 && color.is_some() {
+                        forg_ext = color;
+                    }
+                    else if color.is_some() {
+                        back_ext = color;
+                    }
+                },
+                _ => {}
+            }
         }
+
+        let mut item = CgrcColorItem::new_ext(attrs, forg, back, forg_ext, back_ext);
+        item.set_color_depth(self.depth);
+        item
+    }
+
+    fn forg_param(&self) -> String {
+        match &self.forg_ext {
+            None => (self.forg as u8).to_string(),
+            Some(c) => c.sgr(38, self.depth)
+        }
+    }
+
+    fn back_param(&self) -> String {
+        match &self.back_ext {
+            None => (self.back as u8).to_string(),
+            Some(c) => c.sgr(48, self.depth)
+        }
+    }
+
+    ///
+    /// Attributes changing the text, sorted by code.
+    ///
+    fn text_attrs(&self) -> Vec<CgrcAttrib> {
+        let mut attrs: Vec<CgrcAttrib> = self.attrs.iter()
+            .filter(|a| !matches!(a, CgrcAttrib::CgrcReset | CgrcAttrib::CgrcNone))
+            .copied()
+            .collect();
+        attrs.sort_by_key(|a| *a as i8);
+        attrs
     }
 
     ///
     /// Builds the escape sequence.
     ///
-    fn build_escape_seq(attrs: &HashSet<CgrcAttrib>, forg: &LcLogColor, back: &LcBackColor) -> String {
+    fn build_escape_seq(attrs: &HashSet<CgrcAttrib>,
+                        forg: &LcLogColor,
+                        back: &LcBackColor,
+                        forg_ext: &Option<CgrcExtColor>,
+                        back_ext: &Option<CgrcExtColor>,
+                        depth: CgrcColorDepth) -> String {
         let mut seq = format!("{}[{};{}",
             0x1b as char,
-            *forg as u8,
-            *back as u8,
+            match forg_ext {
+                None => (*forg as u8).to_string(),
+                Some(c) => c.sgr(38, depth)
+            },
+            match back_ext {
+                None => (*back as u8).to_string(),
+                Some(c) => c.sgr(48, depth)
+            },
         );
         for attr in attrs {
             seq += &format!(";{}", *attr as u8);
@@ -247,19 +854,156 @@ impl CgrcColorItem {
             LcBackColor::LcBackColDefault as u8
         );
         for attr in attrs {
-            seq += &format!(";{}", colors_attr_clear(&attr) as u8);
+            seq += &format!(";{}", colors_attr_clear(attr) as u8);
         }
         seq += "m";
         seq
     }
 }
 
+///
+/// Ranges of the groups of a match, the first being the whole match.
+///
+pub type CgrcMatch = Vec<Option<Range<usize>>>;
+
+///
+/// Regex of a rule. Rules are compiled with the regex crate when its syntax
+/// allows it, and with fancy-regex when they need lookaround or
+/// backreferences, or when their matches are replaced, as the templates use
+/// the Python syntax of grc. A standard regex used with a template anyway
+/// is compiled with fancy-regex the first time, and kept for the next
+/// lines.
+///
+#[derive(Clone, Debug)]
+pub enum CgrcRegex {
+    Standard(regex::Regex, OnceLock<Option<Regex>>),
+    Fancy(Regex),
+}
+
+impl CgrcRegex {
+    pub fn new(pattern: &str, fancy: bool) -> Result<CgrcRegex, Box<fancy_regex::Error>> {
+        if !fancy {
+            if let Ok(regex) = regex::Regex::new(pattern) {
+                return Ok(CgrcRegex::Standard(regex, OnceLock::new()));
+            }
+        }
+
+        Regex::new(pattern).map(CgrcRegex::Fancy).map_err(Box::new)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            CgrcRegex::Standard(r, _) => r.as_str(),
+            CgrcRegex::Fancy(r) => r.as_str()
+        }
+    }
+
+    pub fn captures_len(&self) -> usize {
+        match self {
+            CgrcRegex::Standard(r, _) => r.captures_len(),
+            CgrcRegex::Fancy(r) => r.captures_len()
+        }
+    }
+
+    pub fn is_fancy(&self) -> bool {
+        matches!(self, CgrcRegex::Fancy(_))
+    }
+
+    ///
+    /// Returns the ranges of the groups of at most limit matches, together
+    /// with the expansion of the template for each match, if provided.
+    ///
+    pub fn captures(&self, text: &str, limit: usize, template: Option<&str>) -> (Vec<CgrcMatch>, Vec<String>) {
+        match (self, template) {
+            (CgrcRegex::Standard(r, _), None) => {
+                let matches = r.captures_iter(text)
+                    .take(limit)
+                    .map(|captures| captures.iter().map(|c| c.map(|c| c.range())).collect())
+                    .collect();
+                (matches, vec![])
+            },
+            (CgrcRegex::Standard(r, fancy), Some(_)) => match fancy.get_or_init(|| Regex::new(r.as_str()).ok()) {
+                None => self.captures(text, limit, None),
+                Some(fancy) => Self::fancy_captures(fancy, text, limit, template)
+            },
+            (CgrcRegex::Fancy(r), _) => Self::fancy_captures(r, text, limit, template)
+        }
+    }
+
+    // Private portion
+    // ===============
+    fn fancy_captures(regex: &Regex, text: &str, limit: usize, template: Option<&str>) -> (Vec<CgrcMatch>, Vec<String>) {
+        let mut matches: Vec<CgrcMatch> = vec![];
+        let mut expansions: Vec<String> = vec![];
+        for captures in regex.captures_iter(text).flatten().take(limit) {
+            matches.push(captures.iter().map(|c| c.map(|c| c.range())).collect());
+            if let Some(template) = template {
+                expansions.push(Expander::python().expansion(template, &captures));
+            }
+        }
+
+        (matches, expansions)
+    }
+}
+
+///
+/// Set of the rules compiled with the regex crate, used to skip the rules
+/// that cannot match a line with a single pass over it.
+///
+#[derive(Clone, Debug)]
+pub struct CgrcRegexSet {
+    set: RegexSet,
+    indexes: Vec<Option<usize>>,
+}
+
+impl CgrcRegexSet {
+    pub fn new(items: &[CgrcConfItem]) -> Option<CgrcRegexSet> {
+        let mut patterns: Vec<&str> = vec![];
+        let mut indexes: Vec<Option<usize>> = vec![];
+        for item in items {
+            match &item.regex {
+                Some(CgrcRegex::Standard(r, _)) => {
+                    indexes.push(Some(patterns.len()));
+                    patterns.push(r.as_str());
+                },
+                _ => indexes.push(None)
+            }
+        }
+
+        match RegexSet::new(patterns) {
+            Err(e) => {
+                log::warn!("Cannot build the regex set: {}", e);
+                None
+            },
+            Ok(set) => Some(CgrcRegexSet { set, indexes })
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> SetMatches {
+        self.set.matches(text)
+    }
+
+    ///
+    /// Returns false when the rule at index cannot match the text the
+    /// matches were computed on.
+    ///
+    pub fn may_match(&self, matches: &SetMatches, index: usize) -> bool {
+        match self.indexes.get(index) {
+            Some(Some(i)) => matches.matched(*i),
+            _ => true
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CgrcConfItem {
-    pub regex: Option<Regex>,
+    pub regex: Option<CgrcRegex>,
     pub colors: Vec<CgrcColorItem>,
+    pub replace: Option<String>,
     pub skip: Option<bool>,
     pub count_mode: Option<CgrcCountMode>,
+    pub source: String,
+    pub layer: usize,
 }
 
 impl CgrcConfItem {
@@ -267,8 +1011,11 @@ impl CgrcConfItem {
         CgrcConfItem {
             regex: None,
             colors: vec![],
+            replace: None,
             skip: None,
             count_mode: None,
+            source: String::new(),
+            layer: 0,
         }
     }
 }
@@ -276,10 +1023,7 @@ impl CgrcConfItem {
 impl PartialEq for CgrcConfItem {
     fn eq(&self, other: &Self) -> bool {
         match &self.regex {
-            None => match other.regex {
-                None => true,
-                Some(_) => false,
-            },
+            None => other.regex.is_none(),
             Some(r1) => match &other.regex {
                 None => false,
                 Some(r2) => r1.as_str() == r2.as_str(),
@@ -288,7 +1032,293 @@ impl PartialEq for CgrcConfItem {
     }
 }
 
+impl Default for CgrcConfItem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///
+/// Origin of the color of a span: the escape sequences of the input, the
+/// block open from a previous line, or a rule matching the line.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CgrcSpanOrigin {
+    CgrcSpanInput,
+    CgrcSpanBlock,
+    CgrcSpanRule,
+}
+
+///
+/// Portion of a line coloured by a rule.
+///
+#[derive(Clone, Debug)]
+pub struct CgrcColorSpan<'a> {
+    pub range: Range<usize>,
+    pub color: &'a CgrcColorItem,
+    pub origin: CgrcSpanOrigin,
+}
+
+///
+/// Line split in the visible text and the escape sequences it contained.
+/// The colors set by SGR sequences are turned into ranges of the text,
+/// while any other sequence is kept with its position in the text.
+///
+#[derive(Clone, Debug, Default)]
+pub struct CgrcAnsiLine {
+    pub text: String,
+    pub colors: Vec<(Range<usize>, CgrcColorItem)>,
+    pub controls: Vec<(usize, String)>,
+}
+
+impl CgrcAnsiLine {
+    ///
+    /// Creates a line with no escape sequences.
+    ///
+    pub fn plain(text: &str) -> CgrcAnsiLine {
+        CgrcAnsiLine {
+            text: text.to_string(),
+            colors: vec![],
+            controls: vec![],
+        }
+    }
+}
+
+///
+/// Line formatted by the rules, as the text and the colors of its segments,
+/// for the outputs laying out the text themselves. Segments with the
+/// default colors have no color.
+///
+#[derive(Clone, Debug, Default)]
+pub struct CgrcStyledLine {
+    pub text: String,
+    pub segments: Vec<(Range<usize>, Option<CgrcColorItem>)>,
+}
+
+#[derive(Clone, Default)]
 pub struct CgrcConf {
     pub items: Vec<CgrcConfItem>,
     pub description: Option<String>,
+    pub defines: HashMap<String, String>,
+    pub inherited: usize,
+}
+
+impl CgrcConf {
+    pub fn new() -> CgrcConf {
+        CgrcConf {
+            items: vec![],
+            description: None,
+            defines: HashMap::new(),
+            inherited: 0,
+        }
+    }
+
+    ///
+    /// Adds a rule. A rule with the same regex of a rule inherited with
+    /// extends replaces it, keeping its position.
+    ///
+    pub fn push_item(&mut self, item: CgrcConfItem) {
+        match self.items[..self.inherited].iter().position(|i| *i == item) {
+            None => self.items.push(item),
+            Some(index) => self.items[index] = item
+        }
+    }
+
+    ///
+    /// Inherits the rules and the defines of the base configuration. The
+    /// rules are placed before the ones of this configuration, which
+    /// override the base rules with the same regex.
+    ///
+    pub fn extend_from(&mut self, base: CgrcConf) {
+        let count = base.items.len();
+        let mut own = self.items.split_off(self.inherited);
+        for base_item in base.items {
+            match own.iter().position(|i| *i == base_item) {
+                None => self.items.push(base_item),
+                Some(index) => self.items.push(own.remove(index))
+            }
+        }
+        self.items.append(&mut own);
+        self.inherited += count;
+        self.defines.extend(base.defines);
+        if self.description.is_none() {
+            self.description = base.description;
+        }
+    }
+
+    ///
+    /// Appends the rules of another configuration. When stacked, the rules
+    /// are placed in a new layer, so a rule stopping the processing does
+    /// not stop the rules of the other configuration.
+    ///
+    pub fn append(&mut self, mut other: CgrcConf, stacked: bool) {
+        if stacked {
+            let layer = self.items.last().map_or(0, |i| i.layer + 1);
+            for item in &mut other.items {
+                item.layer = layer;
+            }
+        }
+        self.items.append(&mut other.items);
+        self.defines.extend(other.defines);
+        if self.description.is_none() {
+            self.description = other.description;
+        }
+    }
+
+    ///
+    /// Adapts the colors of all the items to the depth of the terminal.
+    ///
+    pub fn set_color_depth(&mut self, depth: CgrcColorDepth) {
+        for item in &mut self.items {
+            for color in &mut item.colors {
+                color.set_color_depth(depth);
+            }
+        }
+    }
+}
+
+///
+/// Entry of the map from command lines to configurations.
+///
+#[derive(Clone, Debug)]
+pub struct CgrcConfMapItem {
+    pub regex: Regex,
+    pub conf: String,
+}
+
+///
+/// State of the parser carried from one line to the next.
+///
+#[derive(Clone, Debug, Default)]
+pub struct CgrcParserState {
+    pub block_color: Option<CgrcColorItem>,
+    /// Color set by the escape sequences of the input and still in effect
+    /// at the end of the previous line.
+    pub input_color: Option<CgrcColorItem>,
+}
+
+impl CgrcParserState {
+    pub fn new() -> CgrcParserState {
+        CgrcParserState {
+            block_color: None,
+            input_color: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(attrs: &[CgrcAttrib], forg: LcLogColor, back: LcBackColor) -> CgrcColorItem {
+        CgrcColorItem::new(attrs.iter().copied().collect(), forg, back)
+    }
+
+    #[test]
+    fn transition_from_default_sets_only_what_differs() {
+        let red = color(&[], LcLogColor::LcForgColRed, LcBackColor::LcBackColDefault);
+        assert_eq!(red.transition_seq(None), "\x1b[31m");
+
+        let plain = color(&[], LcLogColor::LcForgColDefault, LcBackColor::LcBackColDefault);
+        assert_eq!(plain.transition_seq(None), "");
+    }
+
+    #[test]
+    fn transition_to_same_color_is_empty() {
+        let red = color(&[CgrcAttrib::CgrcBright], LcLogColor::LcForgColRed, LcBackColor::LcBackColBlue);
+        assert_eq!(red.transition_seq(Some(&red.clone())), "");
+    }
+
+    #[test]
+    fn transition_adds_attributes_and_changes_colors() {
+        let red = color(&[], LcLogColor::LcForgColRed, LcBackColor::LcBackColDefault);
+        let bold_red = color(&[CgrcAttrib::CgrcBright], LcLogColor::LcForgColRed, LcBackColor::LcBackColDefault);
+        assert_eq!(bold_red.transition_seq(Some(&red)), "\x1b[1m");
+
+        let green_on_blue = color(&[], LcLogColor::LcForgColGreen, LcBackColor::LcBackColBlue);
+        assert_eq!(green_on_blue.transition_seq(Some(&red)), "\x1b[32;44m");
+    }
+
+    #[test]
+    fn transition_removing_attribute_resets() {
+        let red = color(&[], LcLogColor::LcForgColRed, LcBackColor::LcBackColDefault);
+        let bold_red = color(&[CgrcAttrib::CgrcBright], LcLogColor::LcForgColRed, LcBackColor::LcBackColDefault);
+        assert_eq!(red.transition_seq(Some(&bold_red)), "\x1b[0;31m");
+
+        let bold = color(&[CgrcAttrib::CgrcBright], LcLogColor::LcForgColDefault, LcBackColor::LcBackColDefault);
+        let underline = color(&[CgrcAttrib::CgrcUnderline], LcLogColor::LcForgColDefault, LcBackColor::LcBackColDefault);
+        assert_eq!(underline.transition_seq(Some(&bold)), "\x1b[0;4m");
+    }
+
+    fn item(pattern: &str) -> CgrcConfItem {
+        let mut item = CgrcConfItem::new();
+        item.regex = Some(CgrcRegex::new(pattern, false).unwrap());
+        item
+    }
+
+    #[test]
+    fn regexes_fall_back_to_fancy_regex() {
+        assert!(!CgrcRegex::new("a+b", false).unwrap().is_fancy());
+        assert!(CgrcRegex::new("a+b", true).unwrap().is_fancy());
+
+        let lookahead = CgrcRegex::new("foo(?=bar)", false).unwrap();
+        assert!(lookahead.is_fancy());
+        assert_eq!(lookahead.captures("foobaz foobar", 10, None).0, vec![vec![Some(7..10)]]);
+
+        let backreference = CgrcRegex::new("(\\w)\\1", false).unwrap();
+        assert!(backreference.is_fancy());
+        assert_eq!(backreference.captures("abccd", 10, None).0, vec![vec![Some(2..4), Some(2..3)]]);
+    }
+
+    #[test]
+    fn templates_expand_the_groups() {
+        for fancy in [false, true] {
+            let regex = CgrcRegex::new("(\\w+)=(\\d+)", fancy).unwrap();
+            let (matches, expansions) = regex.captures("a=1 b=22", 10, Some("\\2:\\1"));
+            assert_eq!(matches.len(), 2);
+            assert_eq!(expansions, vec![String::from("1:a"), String::from("22:b")]);
+        }
+    }
+
+    #[test]
+    fn regex_set_skips_only_standard_rules() {
+        let items = [item("alpha"), item("beta"), item("(?<=x)gamma")];
+        let set = CgrcRegexSet::new(&items).unwrap();
+        let matches = set.matches("alpha delta");
+        assert!(set.may_match(&matches, 0));
+        assert!(!set.may_match(&matches, 1));
+        assert!(set.may_match(&matches, 2));
+        assert!(set.may_match(&matches, 3));
+    }
+
+    #[test]
+    fn extended_colors_are_parsed() {
+        assert_eq!(CgrcExtColor::parse("color208"), Some(CgrcExtColor::CgrcExtIndexed(208)));
+        assert_eq!(CgrcExtColor::parse("#ff8800"), Some(CgrcExtColor::CgrcExtRgb(255, 136, 0)));
+        assert_eq!(CgrcExtColor::parse("color256"), None);
+        assert_eq!(CgrcExtColor::parse("#ff88"), None);
+        assert_eq!(CgrcExtColor::parse("#gg8800"), None);
+
+        let (colors, unknown) = crate::cgrcparser::CGRCParser::parse_colors("color208 on_#ff8800,on_color17 bold");
+        assert!(unknown.is_empty());
+        assert_eq!(colors[0].forg_ext, Some(CgrcExtColor::CgrcExtIndexed(208)));
+        assert_eq!(colors[0].back_ext, Some(CgrcExtColor::CgrcExtRgb(255, 136, 0)));
+        assert_eq!(colors[1].forg_ext, None);
+        assert_eq!(colors[1].back_ext, Some(CgrcExtColor::CgrcExtIndexed(17)));
+    }
+
+    #[test]
+    fn extended_colors_are_downsampled() {
+        let orange = CgrcExtColor::CgrcExtRgb(255, 136, 0);
+        assert_eq!(orange.sgr(38, CgrcColorDepth::CgrcDepthTrueColor), "38;2;255;136;0");
+        assert_eq!(orange.sgr(48, CgrcColorDepth::CgrcDepth256), "48;5;208");
+        assert_eq!(orange.sgr(38, CgrcColorDepth::CgrcDepth16), "33");
+        assert_eq!(orange.sgr(48, CgrcColorDepth::CgrcDepth16), "43");
+
+        assert_eq!(CgrcExtColor::CgrcExtRgb(128, 128, 128).to_ansi256(), 244);
+        assert_eq!(CgrcExtColor::CgrcExtIndexed(208).sgr(38, CgrcColorDepth::CgrcDepthTrueColor), "38;5;208");
+        assert_eq!(CgrcExtColor::CgrcExtIndexed(196).to_ansi16(), 9);
+        assert_eq!(CgrcExtColor::CgrcExtIndexed(12).sgr(38, CgrcColorDepth::CgrcDepth16), "94");
+        assert_eq!(CgrcExtColor::CgrcExtIndexed(12).sgr(48, CgrcColorDepth::CgrcDepth16), "104");
+    }
 }
@@ -0,0 +1,189 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+///
+/// Kind of error found in a configuration.
+///
+#[derive(Debug)]
+pub enum CgrcConfErrorKind {
+    CgrcErrorIo(io::Error),
+    CgrcErrorInvalidRegex(Box<fancy_regex::Error>),
+    CgrcErrorInvalidCountMode(String),
+    CgrcErrorMissingConf,
+    CgrcErrorUnknownColor(String),
+    CgrcErrorUnknownKey(String),
+    CgrcErrorInvalidSkip(String),
+    CgrcErrorTooManyColors(usize, usize),
+    CgrcErrorUncoloredCaptures(usize, usize),
+    CgrcErrorNoColors,
+    CgrcErrorInvalidDefine,
+    CgrcErrorUndefinedName(String),
+    CgrcErrorIncludeNotFound(String),
+    CgrcErrorIncludeCycle(Vec<String>),
+}
+
+impl fmt::Display for CgrcConfErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CgrcConfErrorKind::CgrcErrorIo(e) => write!(f, "cannot read configuration: {}", e),
+            CgrcConfErrorKind::CgrcErrorInvalidRegex(e) => write!(f, "invalid regex: {}", e),
+            CgrcConfErrorKind::CgrcErrorInvalidCountMode(v) => write!(f, "invalid count mode \"{}\", expected once, more, stop, previous, block or unblock", v),
+            CgrcConfErrorKind::CgrcErrorMissingConf => write!(f, "missing configuration name after the regex"),
+            CgrcConfErrorKind::CgrcErrorUnknownColor(v) => write!(f, "unknown colour \"{}\"", v),
+            CgrcConfErrorKind::CgrcErrorUnknownKey(v) => write!(f, "unknown key \"{}\"", v),
+            CgrcConfErrorKind::CgrcErrorInvalidSkip(v) => write!(f, "invalid skip value \"{}\", expected yes or no", v),
+            CgrcConfErrorKind::CgrcErrorTooManyColors(colors, captures) => write!(f, "{} colours but the regex only has {} capture groups", colors, captures - 1),
+            CgrcConfErrorKind::CgrcErrorUncoloredCaptures(colors, captures) if *colors + 1 == *captures => write!(f, "capture group {} has no colour", colors),
+            CgrcConfErrorKind::CgrcErrorUncoloredCaptures(colors, captures) => write!(f, "capture groups from {} to {} have no colour", colors, captures - 1),
+            CgrcConfErrorKind::CgrcErrorNoColors => write!(f, "rule has no colours"),
+            CgrcConfErrorKind::CgrcErrorInvalidDefine => write!(f, "invalid define, expected define=NAME=pattern"),
+            CgrcConfErrorKind::CgrcErrorUndefinedName(v) => write!(f, "\"{}\" is not defined", v),
+            CgrcConfErrorKind::CgrcErrorIncludeNotFound(v) => write!(f, "cannot find configuration \"{}\" to include", v),
+            CgrcConfErrorKind::CgrcErrorIncludeCycle(v) => write!(f, "configurations include each other: {}", v.join(" -> ")),
+        }
+    }
+}
+
+///
+/// Error found while reading or parsing a configuration. The error carries
+/// the source and the offending line, if any, so it can be reported with a
+/// caret pointing at the problem.
+///
+#[derive(Debug)]
+pub struct CgrcConfError {
+    pub kind: CgrcConfErrorKind,
+    pub source: String,
+    pub line_number: Option<usize>,
+    pub line: Option<String>,
+    pub column: usize,
+    pub width: usize,
+}
+
+impl CgrcConfError {
+    ///
+    /// Creates an error not related to any specific line.
+    ///
+    pub fn new(kind: CgrcConfErrorKind, source: &str) -> CgrcConfError {
+        CgrcConfError {
+            kind,
+            source: source.to_string(),
+            line_number: None,
+            line: None,
+            column: 0,
+            width: 0,
+        }
+    }
+
+    ///
+    /// Creates an error pointing at width chars of the line, starting
+    /// at column. Source and line number are set by the caller with at().
+    ///
+    pub fn in_line(kind: CgrcConfErrorKind, line: &str, column: usize, width: usize) -> CgrcConfError {
+        CgrcConfError {
+            kind,
+            source: String::new(),
+            line_number: None,
+            line: Some(line.to_string()),
+            column,
+            width,
+        }
+    }
+
+    ///
+    /// Sets the location of the error, unless the error already has one,
+    /// e.g. because it comes from an included configuration.
+    ///
+    pub fn at(mut self, source: &str, line_number: usize) -> CgrcConfError {
+        if self.line_number.is_none() {
+            self.source = source.to_string();
+            self.line_number = Some(line_number);
+        }
+        self
+    }
+}
+
+impl CgrcConfError {
+    ///
+    /// Writes the error with the label provided, e.g. error or warning.
+    ///
+    fn render(&self, f: &mut fmt::Formatter<'_>, label: &str) -> fmt::Result {
+        writeln!(f, "{}: {}", label, self.kind)?;
+        let (line_number, line) = match (self.line_number, &self.line) {
+            (Some(n), Some(l)) => (n, l),
+            (Some(n), None) => return write!(f, "  --> {}:{}", self.source, n),
+            _ => return write!(f, "  --> {}", self.source),
+        };
+
+        let gutter = " ".repeat(line_number.to_string().len() + 1);
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.source, line_number, self.column + 1)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, " {} | {}", line_number, line)?;
+        let padding: String = line.chars()
+            .take(self.column)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "{} | {}{}", gutter, padding, "^".repeat(self.width.max(1)))
+    }
+}
+
+impl fmt::Display for CgrcConfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.render(f, "error")
+    }
+}
+
+impl Error for CgrcConfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            CgrcConfErrorKind::CgrcErrorIo(e) => Some(e),
+            CgrcConfErrorKind::CgrcErrorInvalidRegex(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Severity of a diagnostic reported when checking a configuration.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CgrcConfSeverity {
+    CgrcSeverityWarning,
+    CgrcSeverityError,
+}
+
+///
+/// Problem found when checking a configuration.
+///
+#[derive(Debug)]
+pub struct CgrcConfDiagnostic {
+    pub severity: CgrcConfSeverity,
+    pub error: CgrcConfError,
+}
+
+impl fmt::Display for CgrcConfDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            CgrcConfSeverity::CgrcSeverityWarning => self.error.render(f, "warning"),
+            CgrcConfSeverity::CgrcSeverityError => self.error.render(f, "error"),
+        }
+    }
+}
@@ -0,0 +1,386 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::borrow::Cow;
+use std::io::{self, BufRead};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+// Bytes that are not valid UTF-8 are mapped to the last private use
+// characters of plane 16, so they can go through the rules and be
+// written back unchanged. The characters of the input in the same range
+// are mapped to their own bytes in the same way, so that they are not
+// mistaken for invalid bytes.
+const RAW_BYTE_BASE: u32 = 0x10FF00;
+
+///
+/// Returns true if the character stands for a byte of the input written
+/// back as it is: a byte that is not valid UTF-8, or a byte of a character
+/// in the range used for them.
+///
+pub fn is_raw_byte(c: char) -> bool {
+    (RAW_BYTE_BASE + 0x80..=RAW_BYTE_BASE + 0xff).contains(&(c as u32))
+}
+
+///
+/// Returns the byte the character stands for, if it is a raw byte.
+///
+pub fn raw_byte(c: char) -> Option<u8> {
+    match is_raw_byte(c) {
+        true => Some((c as u32 - RAW_BYTE_BASE) as u8),
+        false => None
+    }
+}
+
+///
+/// Returns true if the character at index of the text stands for a C1
+/// control byte, 0x80 to 0x9f, that is not valid UTF-8. Terminals may
+/// interpret these bytes as controls, e.g. 0x9b as the start of a CSI
+/// sequence.
+///
+pub fn is_raw_control(text: &str, index: usize) -> bool {
+    let c = match text[index..].chars().next() {
+        None => return false,
+        Some(v) => v
+    };
+    if !matches!(raw_byte(c), Some(0x80..=0x9f)) {
+        return false;
+    }
+
+    // Bytes of a character mapped to its own bytes form valid UTF-8 with
+    // the raw bytes around them.
+    let before: Vec<u8> = text[..index].chars().rev().take(3).map_while(raw_byte).collect();
+    let after: Vec<u8> = text[index..].chars().take(4).map_while(raw_byte).collect();
+    let window: Vec<u8> = before.iter().rev().chain(after.iter()).copied().collect();
+    let position = before.len();
+    (position.saturating_sub(3)..=position)
+        .filter(|start| start + 4 <= window.len())
+        .all(|start| std::str::from_utf8(&window[start..start + 4]).is_err())
+}
+
+///
+/// Returns the text the raw bytes in it stand for: the characters mapped
+/// to their bytes are restored, while invalid bytes are replaced by the
+/// replacement character.
+///
+pub fn decode_raw_bytes(text: &str) -> Cow<'_, str> {
+    if !text.contains(is_raw_byte) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut bytes: Vec<u8> = vec![];
+    for c in text.chars() {
+        match raw_byte(c) {
+            Some(b) => bytes.push(b),
+            None => {
+                decoded += &String::from_utf8_lossy(&bytes);
+                bytes.clear();
+                decoded.push(c);
+            }
+        }
+    }
+    decoded += &String::from_utf8_lossy(&bytes);
+
+    Cow::Owned(decoded)
+}
+
+///
+/// Looks up an encoding by one of its labels, e.g. latin1 or utf-16le.
+///
+pub fn encoding_for_label(label: &str) -> Result<&'static Encoding, String> {
+    match Encoding::for_label(label.trim().as_bytes()) {
+        None => Err(format!("unknown encoding \"{}\"", label)),
+        Some(v) => Ok(v)
+    }
+}
+
+///
+/// Line read from the input, decoded to text. Bytes not valid in the
+/// encoding of the input are kept, so they can be written back as they are.
+///
+pub struct CgrcInputLine {
+    pub text: String,
+    raw_bytes: bool,
+}
+
+impl CgrcInputLine {
+    ///
+    /// Decodes a line of UTF-8 text. Invalid bytes are kept in the text as
+    /// characters that do not match anything but the regexes matching any
+    /// character, e.g. ".", and are restored by restore_bytes().
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> CgrcInputLine {
+        let mut line = CgrcInputLine {
+            text: String::with_capacity(bytes.len()),
+            raw_bytes: false,
+        };
+        for chunk in bytes.utf8_chunks() {
+            line.push_text(chunk.valid());
+            for byte in chunk.invalid() {
+                line.push_raw_byte(*byte);
+            }
+        }
+
+        line
+    }
+
+    ///
+    /// Returns the bytes to write for the formatted line, restoring the
+    /// invalid bytes of the input.
+    ///
+    pub fn restore_bytes<'a>(&self, formatted: &'a str) -> Cow<'a, [u8]> {
+        if !self.raw_bytes {
+            return Cow::Borrowed(formatted.as_bytes());
+        }
+
+        let mut bytes = Vec::with_capacity(formatted.len());
+        let mut buffer = [0u8; 4];
+        for c in formatted.chars() {
+            match raw_byte(c) {
+                Some(b) => bytes.push(b),
+                None => bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes())
+            }
+        }
+
+        Cow::Owned(bytes)
+    }
+
+    // Private portion
+    // ===============
+    fn push_text(&mut self, text: &str) {
+        // The characters in the range of the raw bytes start with 0xf4.
+        if !text.as_bytes().contains(&0xf4) {
+            self.text.push_str(text);
+            return;
+        }
+
+        let mut buffer = [0u8; 4];
+        for c in text.chars() {
+            match is_raw_byte(c) {
+                true => {
+                    for byte in c.encode_utf8(&mut buffer).bytes() {
+                        self.push_raw_byte(byte);
+                    }
+                },
+                false => self.text.push(c)
+            }
+        }
+    }
+
+    fn push_raw_byte(&mut self, byte: u8) {
+        self.raw_bytes = true;
+        self.text.push(char::from_u32(RAW_BYTE_BASE + byte as u32).unwrap_or(char::REPLACEMENT_CHARACTER));
+    }
+}
+
+impl AsRef<str> for CgrcInputLine {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
+///
+/// Reads lines from a reader, decoding them from the encoding provided.
+/// Without an encoding, the input is read as UTF-8, keeping the invalid
+/// bytes. Lines can end with \n or \r\n, which are not included.
+///
+pub struct CgrcLineReader<R: BufRead> {
+    inner: R,
+    encoding: Option<&'static Encoding>,
+    buffer: Vec<u8>,
+    at_start: bool,
+}
+
+impl<R: BufRead> CgrcLineReader<R> {
+    pub fn new(inner: R, encoding: Option<&'static Encoding>) -> CgrcLineReader<R> {
+        CgrcLineReader {
+            inner,
+            encoding: encoding.filter(|e| *e != UTF_8),
+            buffer: vec![],
+            at_start: true,
+        }
+    }
+
+    ///
+    /// Reads the next line. Returns None at the end of the input.
+    ///
+    pub fn read_line(&mut self) -> io::Result<Option<CgrcInputLine>> {
+        self.buffer.clear();
+        let read = match self.encoding {
+            Some(e) if e == UTF_16LE || e == UTF_16BE => self.read_utf16_line(e == UTF_16LE)?,
+            _ => self.inner.read_until(b'\n', &mut self.buffer)?
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+
+        let at_start = std::mem::replace(&mut self.at_start, false);
+        let encoding = match self.encoding {
+            None => {
+                if self.buffer.last() == Some(&b'\n') {
+                    self.buffer.pop();
+                    if self.buffer.last() == Some(&b'\r') {
+                        self.buffer.pop();
+                    }
+                }
+                return Ok(Some(CgrcInputLine::from_bytes(&self.buffer)));
+            },
+            Some(v) => v
+        };
+
+        let (text, _) = match at_start {
+            true => encoding.decode_with_bom_removal(&self.buffer),
+            false => encoding.decode_without_bom_handling(&self.buffer)
+        };
+        let mut text: &str = &text;
+        if let Some(v) = text.strip_suffix('\n') {
+            text = v.strip_suffix('\r').unwrap_or(v);
+        }
+
+        let mut line = CgrcInputLine {
+            text: String::with_capacity(text.len()),
+            raw_bytes: false,
+        };
+        line.push_text(text);
+        Ok(Some(line))
+    }
+
+    // Private portion
+    // ===============
+    fn read_utf16_line(&mut self, little_endian: bool) -> io::Result<usize> {
+        loop {
+            if self.inner.read_until(b'\n', &mut self.buffer)? == 0 {
+                return Ok(self.buffer.len());
+            }
+
+            // The byte found may be half of any code unit: a newline is
+            // 0a 00 in little endian and 00 0a in big endian.
+            let index = self.buffer.len() - 1;
+            if index.is_multiple_of(2) && little_endian {
+                let mut next = [0u8; 1];
+                match self.inner.read(&mut next)? {
+                    0 => return Ok(self.buffer.len()),
+                    _ => self.buffer.push(next[0])
+                }
+                if next[0] == 0 {
+                    return Ok(self.buffer.len());
+                }
+            }
+            else if !index.is_multiple_of(2) && !little_endian && self.buffer[index - 1] == 0 {
+                return Ok(self.buffer.len());
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CgrcLineReader<R> {
+    type Item = io::Result<CgrcInputLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_line().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+    use std::io::Cursor;
+
+    fn read_lines(bytes: &[u8], encoding: Option<&'static Encoding>) -> Vec<String> {
+        CgrcLineReader::new(Cursor::new(bytes.to_vec()), encoding)
+            .map(|line| line.unwrap().text)
+            .collect()
+    }
+
+    #[test]
+    fn valid_text_is_unchanged() {
+        let line = CgrcInputLine::from_bytes("héllo wörld".as_bytes());
+        assert_eq!(line.text, "héllo wörld");
+        assert_eq!(line.restore_bytes(&line.text), "héllo wörld".as_bytes());
+    }
+
+    #[test]
+    fn invalid_bytes_are_restored() {
+        let bytes = b"a\xff b\x9b31m c\xe2\x82";
+        let line = CgrcInputLine::from_bytes(bytes);
+        assert_eq!(line.text.chars().filter(|c| is_raw_byte(*c)).count(), 4);
+        assert_eq!(line.restore_bytes(&line.text).as_ref(), bytes);
+        assert_eq!(line.restore_bytes(&format!("\x1b[31m{}\x1b[0m", line.text)).as_ref(),
+                   [b"\x1b[31m".as_slice(), bytes, b"\x1b[0m"].concat());
+    }
+
+    #[test]
+    fn characters_in_the_raw_range_are_restored() {
+        let bytes = "a\u{10FF80} b\u{10FFFF}".as_bytes();
+        let mixed = [b"\xff ".as_slice(), bytes].concat();
+        for bytes in [bytes, &mixed] {
+            let line = CgrcInputLine::from_bytes(bytes);
+            assert_eq!(line.restore_bytes(&line.text).as_ref(), bytes);
+        }
+    }
+
+    #[test]
+    fn raw_bytes_decode_to_text() {
+        let line = CgrcInputLine::from_bytes(b"a\xff \xf4\x8f\xbe\x80 b");
+        assert_eq!(decode_raw_bytes(&line.text), "a\u{FFFD} \u{10FF80} b");
+        assert_eq!(decode_raw_bytes("plain"), "plain");
+    }
+
+    #[test]
+    fn only_invalid_c1_bytes_are_controls() {
+        let line = CgrcInputLine::from_bytes(b"\x9b \xff\x85 \xf4\x8f\xbe\x80");
+        let controls: Vec<usize> = line.text.char_indices()
+            .filter(|(i, _)| is_raw_control(&line.text, *i))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(controls, vec![0, 9]);
+    }
+
+    #[test]
+    fn utf8_lines_drop_their_terminators() {
+        assert_eq!(read_lines(b"a\r\nb\n\nc", None), vec!["a", "b", "", "c"]);
+    }
+
+    #[test]
+    fn utf16le_lines_split_on_newline_code_units() {
+        // U+010A is 0a 01 in little endian, which is not a newline.
+        let text = "\u{FEFF}a\u{010A}b\r\nc\n\u{10FF80}";
+        let bytes: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+        let lines = read_lines(&bytes, Some(UTF_16LE));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "a\u{010A}b");
+        assert_eq!(lines[1], "c");
+
+        let line = CgrcLineReader::new(Cursor::new(bytes), Some(UTF_16LE)).nth(2).unwrap().unwrap();
+        assert_eq!(line.restore_bytes(&line.text).as_ref(), "\u{10FF80}".as_bytes());
+    }
+
+    #[test]
+    fn utf16be_lines_split_on_newline_code_units() {
+        // U+0A01 is 0a 01 in big endian, and U+010A is 01 0a.
+        let text = "\u{FEFF}\u{0A01}\u{010A}\nb\r\n";
+        let bytes: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_be_bytes()).collect();
+        assert_eq!(read_lines(&bytes, Some(UTF_16BE)), vec!["\u{0A01}\u{010A}", "b"]);
+    }
+
+    #[test]
+    fn single_byte_encodings_are_decoded() {
+        assert_eq!(read_lines(b"caf\xe9\nna\xefve\n", Some(WINDOWS_1252)), vec!["café", "naïve"]);
+    }
+}
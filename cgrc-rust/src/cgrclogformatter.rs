@@ -0,0 +1,157 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use env_logger::fmt::Formatter;
+use log::{Level, Record};
+use crate::cgrccolorizer::Colorizer;
+use crate::cgrcdata::CgrcColorItem;
+use crate::cgrcparser::CGRCParser;
+
+///
+/// Formatter for env_logger colorizing the log records with the rules of a
+/// configuration. The level is always styled, so records are readable also
+/// when no configuration is used:
+///
+/// ```no_run
+/// use cgrc::{Colorizer, ColorizingFormatter};
+///
+/// let formatter = ColorizingFormatter::new(Colorizer::load("prio").unwrap());
+/// env_logger::Builder::from_default_env()
+///     .format(move |buf, record| formatter.format(buf, record))
+///     .init();
+/// ```
+///
+pub struct ColorizingFormatter {
+    colorizer: Option<Mutex<Colorizer>>,
+    level_colors: Vec<CgrcColorItem>,
+}
+
+impl Default for ColorizingFormatter {
+    fn default() -> ColorizingFormatter {
+        ColorizingFormatter {
+            colorizer: None,
+            level_colors: Self::parse_level_colors(),
+        }
+    }
+}
+
+impl ColorizingFormatter {
+    ///
+    /// Creates a formatter applying the rules of the colorizer to the
+    /// messages of the records.
+    ///
+    pub fn new(colorizer: Colorizer) -> ColorizingFormatter {
+        ColorizingFormatter {
+            colorizer: Some(Mutex::new(colorizer)),
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Writes the record like the default format of env_logger. Each line
+    /// of the message is colorized separately, and the record is dropped
+    /// when all of its lines are skipped by the rules.
+    ///
+    pub fn format(&self, buf: &mut Formatter, record: &Record) -> io::Result<()> {
+        let timestamp = buf.timestamp();
+        self.write_record(buf, &timestamp, record)
+    }
+
+    // Private portion
+    // ===============
+    fn write_record<W: Write>(&self, out: &mut W, timestamp: &dyn Display, record: &Record) -> io::Result<()> {
+        let message = record.args().to_string();
+        let lines: Vec<String> = match &self.colorizer {
+            None => message.lines().map(String::from).collect(),
+            Some(colorizer) => {
+                let mut colorizer = colorizer.lock().unwrap_or_else(|e| e.into_inner());
+                message.lines()
+                    .filter_map(|line| match line.is_empty() {
+                        true => Some(String::new()),
+                        false => colorizer.colorize_line(line)
+                    })
+                    .collect()
+            }
+        };
+        if lines.is_empty() && !message.is_empty() {
+            return Ok(());
+        }
+
+        let level = match self.level_colors.get(record.level() as usize - 1) {
+            None => format!("{:<5}", record.level()),
+            Some(color) => format!("{}{:<5}\x1b[0m", color.escape_seq, record.level())
+        };
+        writeln!(out, "[{} {} {}] {}", timestamp, level, record.target(), lines.join("\n"))
+    }
+
+    ///
+    /// Parses the colors of the levels, indexed from Error.
+    ///
+    fn parse_level_colors() -> Vec<CgrcColorItem> {
+        Level::iter()
+            .filter_map(|level| CGRCParser::parse_colors(Self::level_colors(level)).0.into_iter().next())
+            .collect()
+    }
+
+    fn level_colors(level: Level) -> &'static str {
+        match level {
+            Level::Error => "bold red",
+            Level::Warn => "bold yellow",
+            Level::Info => "green",
+            Level::Debug => "blue",
+            Level::Trace => "bright_black"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(formatter: &ColorizingFormatter, level: Level, message: std::fmt::Arguments) -> String {
+        let mut out: Vec<u8> = vec![];
+        let record = Record::builder().args(message).level(level).target("test").build();
+        formatter.write_record(&mut out, &"T", &record).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn formatter() -> ColorizingFormatter {
+        let conf = CGRCParser::parse_conf_string("regexp=secret\nskip=yes\n-\nregexp=b\ncolours=blue\n".to_string(), "test").unwrap();
+        ColorizingFormatter::new(Colorizer::new(conf))
+    }
+
+    #[test]
+    fn levels_are_styled_without_rules() {
+        let formatter = ColorizingFormatter::default();
+        assert_eq!(format(&formatter, Level::Warn, format_args!("ab")), "[T \x1b[33;49;1mWARN \x1b[0m test] ab\n");
+    }
+
+    #[test]
+    fn skipped_records_are_dropped() {
+        assert_eq!(format(&formatter(), Level::Info, format_args!("a secret")), "");
+    }
+
+    #[test]
+    fn message_lines_are_colorized_separately() {
+        let out = format(&formatter(), Level::Error, format_args!("ab\n\nthe secret\ncb"));
+        assert_eq!(out, "[T \x1b[31;49;1mERROR\x1b[0m test] a\x1b[34mb\x1b[0m\n\nc\x1b[34mb\x1b[0m\n");
+    }
+}
@@ -16,113 +16,310 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::{process, fs::File, io::{BufReader, BufRead, Cursor}, collections::HashSet};
-use std::ptr;
+use std::{fs::File, io::{BufReader, BufRead, Cursor}, collections::{HashMap, HashSet}};
+use std::path::Path;
+use std::ops::Range;
+use std::borrow::Cow;
 use fancy_regex::Regex;
+use crate::cgrcconfmanager::CGRCConfManager;
+use crate::cgrcinput::{is_raw_byte, is_raw_control, raw_byte};
+use crate::cgrcerror::{CgrcConfError, CgrcConfErrorKind};
+use crate::cgrcrenderer::{CgrcAnsiRenderer, CgrcRenderer};
 use crate::cgrcdata::{
+    CgrcAnsiLine,
+    CgrcAnsiPolicy,
+    CgrcColorDepth,
     CgrcColorItem,
+    CgrcColorSpan,
+    CgrcSpanOrigin,
     CgrcCountMode,
     CgrcConfItem,
     CgrcConf,
+    CgrcConfMapItem,
     CgrcAttrib,
+    CgrcExtColor,
+    CgrcMatch,
+    CgrcRegex,
+    CgrcRegexSet,
+    CgrcParserState,
+    CgrcSanitizeMode,
     LcLogColor,
     LcBackColor,
     COLORS_ATTRS,
     COLORS_FORG,
-    COLORS_BACK
+    COLORS_BACK,
+    CONF_KEYS
 };
 
+// Segments of a line with their color, None for the default one.
+type CgrcSegments<'a> = Vec<(Range<usize>, Option<Cow<'a, CgrcColorItem>>)>;
+
 pub struct CGRCParser {}
 
 impl CGRCParser {
     ///
     /// Parsers a conf file.
     /// 
-    pub fn parse_conf(conf_file: &String) -> CgrcConf {
+    pub fn parse_conf(conf_file: &str) -> Result<CgrcConf, CgrcConfError> {
         let file = match File::open(conf_file) {
-            Err(e) => {
-                log::error!("Failed to open conf file {conf_file}: {e}");
-                process::exit(1);
-            },
-            Ok(f) => {
-                f
-            }
+            Err(e) => return Err(CgrcConfError::new(CgrcConfErrorKind::CgrcErrorIo(e), conf_file)),
+            Ok(f) => f
         };
 
         let reader = BufReader::new(file);
-        return Self::parse_conf_lines(reader);
+        Self::parse_conf_lines(reader, conf_file)
+    }
+
+    ///
+    /// Parse lines from a buffered reader. The source is only used to
+    /// report errors.
+    /// 
+    pub fn parse_conf_lines<T: BufRead>(reader: T, source: &str) -> Result<CgrcConf, CgrcConfError> {
+        Self::parse_conf_lines_included(reader, &[source.to_string()])
     }
 
     ///
-    /// Parse lines from a buffered reader.
+    /// Parse lines from a buffered reader. The stack contains the sources
+    /// of the configurations including this one, the last being the source
+    /// of the lines.
     /// 
-    pub fn parse_conf_lines<T: BufRead>(reader: T) -> CgrcConf {
+    fn parse_conf_lines_included<T: BufRead>(reader: T, stack: &[String]) -> Result<CgrcConf, CgrcConfError> {
+        let source = stack.last().unwrap();
         let mut item = CgrcConfItem::new();
-        let mut conf = CgrcConf {
-            description: None,
-            items: vec![]
-        };
-        for line in reader.lines() {
-            match line {
-                Err(e) => {
-                    log::error!("Failed to read conf file: {e}");
-                    process::exit(1);
-                },
-                Ok(line) => {
-                    if CGRCParser::parse_conf_line(&line, &mut conf,  &mut item) {
-                        conf.items.push(item.clone());
-                        item = CgrcConfItem::new();
-                    }
-                }
+        let mut conf = CgrcConf::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = match line {
+                Err(e) => return Err(CgrcConfError::new(CgrcConfErrorKind::CgrcErrorIo(e), source)),
+                Ok(line) => line
+            };
+            let item_complete = CGRCParser::parse_conf_line(&line, &mut conf, &mut item, stack)
+                .map_err(|e| e.at(source, index + 1))?;
+            if item.regex.is_some() && item.source.is_empty() {
+                item.source = format!("{}:{}", source, index + 1);
+            }
+            if item_complete {
+                conf.push_item(item);
+                item = CgrcConfItem::new();
             }
         }
 
         if item.regex.is_some() {
-            conf.items.push(item.clone());
+            conf.push_item(item);
         }
 
-        conf
+        Ok(conf)
     }
 
     ///
     /// Parses a configuration from a string.
     /// 
-    pub fn parse_conf_string(conf: String) -> CgrcConf {
+    pub fn parse_conf_string(conf: String, source: &str) -> Result<CgrcConf, CgrcConfError> {
         let cursor = Cursor::new(conf);
         let reader = BufReader::new(cursor);
-        return CGRCParser::parse_conf_lines(reader);
+        CGRCParser::parse_conf_lines(reader, source)
     }
 
-    // Private portion
-    // ===============
-    fn parse_conf_line(line: &String, conf: &mut CgrcConf, item: &mut CgrcConfItem) -> bool {
+    ///
+    /// Parses a rule written on a single line, e.g. on the command line,
+    /// with the keys separated by semicolons. Semicolons not followed by
+    /// a key are part of the value, so they can be used in regexes.
+    /// Unknown colours are reported as errors, like in --highlight.
+    /// 
+    pub fn parse_inline_rule(rule: &str, source: &str) -> Result<CgrcConf, CgrcConfError> {
+        let mut lines: Vec<(usize, &str)> = vec![];
+        let mut start = 0;
+        for (index, _) in rule.match_indices(';') {
+            let next = rule[index + 1..].trim_start().to_lowercase();
+            if CONF_KEYS.iter().any(|k| next.starts_with(k) && next[k.len()..].starts_with('=')) {
+                lines.push((start, &rule[start..index]));
+                start = index + 1;
+            }
+        }
+        lines.push((start, &rule[start..]));
+
+        for (offset, line) in &lines {
+            let trimmed = line.trim_start();
+            let value = match trimmed.split_once('=') {
+                Some((key, value)) if key.to_lowercase() == "colours" => value,
+                _ => continue
+            };
+            let value_offset = offset + line.len() - value.len();
+            if let Some((color_offset, color)) = Self::find_unknown_color(value) {
+                let column = value_offset + color_offset;
+                let error = CgrcConfError::in_line(
+                    CgrcConfErrorKind::CgrcErrorUnknownColor(color.to_string()),
+                    rule,
+                    rule[..column].chars().count(),
+                    color.chars().count()
+                );
+                return Err(error.at(source, 1));
+            }
+        }
+
+        let lines: Vec<&str> = lines.iter().map(|(_, line)| line.trim_start()).collect();
+        Self::parse_conf_string(lines.join("\n"), source)
+    }
+
+    ///
+    /// Parses a highlight in the form WORD:colours, where the word is
+    /// matched literally. When the text after the last colon contains no
+    /// letters, e.g. in 12:30, the whole text is the word and it is
+    /// highlighted in bold red.
+    /// 
+    pub fn parse_highlight(highlight: &str) -> Result<CgrcConfItem, CgrcConfError> {
+        let (word, colors) = match highlight.rsplit_once(':') {
+            Some((word, colors)) if colors.chars().any(|c| c.is_ascii_alphabetic()) => (word, colors),
+            _ => (highlight, "bold red")
+        };
+
+        if let Some((color_offset, color)) = Self::find_unknown_color(colors) {
+            let column = highlight.len() - colors.len() + color_offset;
+            let error = CgrcConfError::in_line(
+                CgrcConfErrorKind::CgrcErrorUnknownColor(color.to_string()),
+                highlight,
+                highlight[..column].chars().count(),
+                color.chars().count()
+            );
+            return Err(error.at("--highlight", 1));
+        }
+
+        let (colors, _) = Self::parse_colors(&colors.to_lowercase());
+
+        let mut item = CgrcConfItem::new();
+        item.regex = match CgrcRegex::new(&fancy_regex::escape(word), false) {
+            Err(e) => return Err(Self::regex_error(*e, highlight, 0).at("--highlight", 1)),
+            Ok(r) => Some(r)
+        };
+        item.colors = colors;
+        item.source = String::from("--highlight");
+        Ok(item)
+    }
+
+    ///
+    /// Parses a map from command lines to configurations. The format is the
+    /// same of grc.conf: a regex line followed by the configuration name.
+    /// 
+    pub fn parse_conf_map_string(conf_map: &str, source: &str) -> Result<Vec<CgrcConfMapItem>, CgrcConfError> {
+        let mut items: Vec<CgrcConfMapItem> = vec![];
+        let mut regex: Option<(Regex, usize, &str)> = None;
+        for (index, line) in conf_map.lines().enumerate() {
+            let trimmed_line = line.trim();
+            if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
+                continue;
+            }
+
+            match regex.take() {
+                None => {
+                    let offset = line.len() - line.trim_start().len();
+                    regex = match Regex::new(trimmed_line) {
+                        Err(e) => return Err(Self::regex_error(e, line, offset).at(source, index + 1)),
+                        Ok(r) => Some((r, index, line))
+                    };
+                },
+                Some((r, _, _)) => {
+                    items.push(CgrcConfMapItem {
+                        regex: r,
+                        conf: trimmed_line.to_string()
+                    });
+                }
+            }
+        }
+
+        if let Some((_, index, line)) = regex {
+            let error = CgrcConfError::in_line(CgrcConfErrorKind::CgrcErrorMissingConf, line, line.chars().count(), 1);
+            return Err(error.at(source, index + 1));
+        }
+
+        Ok(items)
+    }
+
+    ///
+    /// Parses a line of a configuration into the item being built, or into
+    /// the configuration for directives like define= and include=. The
+    /// stack holds the sources of the configurations being included.
+    /// Returns true when the item is complete.
+    /// 
+    pub fn parse_conf_line(line: &str, conf: &mut CgrcConf, item: &mut CgrcConfItem, stack: &[String]) -> Result<bool, CgrcConfError> {
         let lline = line.to_lowercase();
 
+        if lline.starts_with("define=") {
+            let definition = &line["define=".len()..];
+            let (name, pattern) = match definition.split_once('=') {
+                Some((name, pattern)) if Self::is_valid_name(name) => (name, pattern),
+                _ => return Err(CgrcConfError::in_line(CgrcConfErrorKind::CgrcErrorInvalidDefine, line, 0, line.chars().count()))
+            };
+            let pattern = Self::expand_defines(pattern, &conf.defines)
+                .map_err(|e| Self::offset_error(e, line, "define=".len() + name.len() + 1))?;
+            conf.defines.insert(name.to_string(), pattern);
+            return Ok(false);
+        }
+
+        if lline.starts_with("include=") {
+            let name = line["include=".len()..].trim();
+            let included = Self::parse_include(name, stack)
+                .map_err(|e| Self::offset_error(e, line, "include=".len()))?;
+            // The rules are spliced in place, so the rule being parsed
+            // must come before them.
+            if item.regex.is_some() {
+                conf.push_item(std::mem::take(item));
+            }
+            conf.items.extend(included.items);
+            conf.defines.extend(included.defines);
+            return Ok(false);
+        }
+
+        if lline.starts_with("extends=") {
+            let name = line["extends=".len()..].trim();
+            let base = Self::parse_include(name, stack)
+                .map_err(|e| Self::offset_error(e, line, "extends=".len()))?;
+            if item.regex.is_some() {
+                conf.push_item(std::mem::take(item));
+            }
+            conf.extend_from(base);
+            return Ok(false);
+        }
+
         if lline.starts_with("desc=") {
             conf.description = Some(lline.replace("desc=", ""));
-            return false;
+            return Ok(false);
         }
 
         if lline.starts_with("regexp=") {
-            item.regex = match Regex::new(format!("{}", line.replace("regexp=", "")).as_str()) {
-                Err(e) => {
-                    log::error!("Failed to parse regex: {line}");
-                    log::error!("{}", e.to_string());
-                    process::exit(1);
-                },
+            let pattern = Self::expand_defines(&line["regexp=".len()..], &conf.defines)
+                .map_err(|e| Self::offset_error(e, line, "regexp=".len()))?;
+            item.regex = match CgrcRegex::new(&pattern, item.replace.is_some()) {
+                Err(e) if pattern == line["regexp=".len()..] => return Err(Self::regex_error(*e, line, "regexp=".len())),
+                Err(e) => return Err(CgrcConfError::in_line(
+                    CgrcConfErrorKind::CgrcErrorInvalidRegex(e),
+                    line,
+                    "regexp=".len(),
+                    line.chars().count() - "regexp=".len()
+                )),
                 Ok(r) => Some(r)
             };
-            return false;
+            return Ok(false);
+        }
+
+        if lline.starts_with("replace=") {
+            // Replacements need fancy-regex for the Python syntax.
+            if let Some(regex) = item.regex.as_ref().filter(|r| !r.is_fancy()) {
+                item.regex = match CgrcRegex::new(regex.as_str(), true) {
+                    Err(e) => return Err(CgrcConfError::in_line(CgrcConfErrorKind::CgrcErrorInvalidRegex(e), line, 0, line.chars().count())),
+                    Ok(r) => Some(r)
+                };
+            }
+            item.replace = Some(line["replace=".len()..].to_string());
+            return Ok(false);
         }
 
         if lline.starts_with("colours=") {
-            item.colors.append(&mut CGRCParser::parse_colors(&lline.replace("colours=", "")));
-            return false;
+            item.colors.append(&mut CGRCParser::parse_colors(&lline.replace("colours=", "")).0);
+            return Ok(false);
         }
 
         if lline.starts_with("skip=") {
             item.skip = Some(lline.to_lowercase() == "skip=yes");
-            return false;
+            return Ok(false);
         }
 
         if lline.starts_with("count=") {
@@ -146,139 +343,931 @@ impl CGRCParser {
                     item.count_mode = Some(CgrcCountMode::CgrcCountUnblock)
                 }
                 _default => {
-                    log::error!("Invalid count mode");
-                    process::exit(1);
+                    let value = &line["count=".len()..];
+                    return Err(CgrcConfError::in_line(
+                        CgrcConfErrorKind::CgrcErrorInvalidCountMode(value.to_string()),
+                        line,
+                        "count=".len(),
+                        value.chars().count()
+                    ));
+                }
+            }
+            return Ok(false);
+        }
+
+        Ok(item.regex.is_some())
+    }
+
+    // Private portion
+    // ===============
+    ///
+    /// Finds the first unknown colour in a list of colours, returning its
+    /// byte offset in the list and the colour as it was written.
+    /// 
+    fn find_unknown_color(colors: &str) -> Option<(usize, &str)> {
+        let mut offset = 0;
+        for option in colors.split([',', ' ']) {
+            let (_, unknown) = Self::parse_colors(&option.to_lowercase());
+            if !unknown.is_empty() {
+                return Some((offset, option));
+            }
+            offset += option.len() + 1;
+        }
+        None
+    }
+
+    ///
+    /// Expands the ${NAME} references in the pattern with the defines.
+    /// $${NAME} is left as a literal ${NAME}.
+    /// 
+    fn expand_defines(pattern: &str, defines: &HashMap<String, String>) -> Result<String, CgrcConfError> {
+        let mut expanded = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find("${") {
+            let end = match rest[start..].find('}') {
+                Some(v) => start + v,
+                None => break
+            };
+            let name = &rest[start + 2..end];
+            if rest[..start].ends_with('$') {
+                expanded += &rest[..start - 1];
+                expanded += &rest[start..end + 1];
+                rest = &rest[end + 1..];
+                continue;
+            }
+            if !Self::is_valid_name(name) {
+                expanded += &rest[..end + 1];
+                rest = &rest[end + 1..];
+                continue;
+            }
+
+            match defines.get(name) {
+                None => {
+                    let column = pattern[..pattern.len() - rest.len() + start].chars().count();
+                    return Err(CgrcConfError::in_line(
+                        CgrcConfErrorKind::CgrcErrorUndefinedName(name.to_string()),
+                        pattern,
+                        column,
+                        name.chars().count() + 3
+                    ));
+                },
+                Some(v) => {
+                    expanded += &rest[..start];
+                    expanded += v;
+                    rest = &rest[end + 1..];
                 }
             }
-            return false;
         }
 
-        return item.regex.is_some();
+        expanded += rest;
+        Ok(expanded)
     }
 
-    fn parse_colors(line: &String) -> Vec<CgrcColorItem> {
+    fn is_valid_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            _ => false
+        }
+    }
+
+    ///
+    /// Parses the configuration to include or extend, resolved by name like any
+    /// other configuration or as a path if it contains a separator.
+    /// 
+    fn parse_include(name: &str, stack: &[String]) -> Result<CgrcConf, CgrcConfError> {
+        let width = name.chars().count();
+        if stack.iter().any(|s| s == name) {
+            let mut cycle = stack.to_vec();
+            cycle.push(name.to_string());
+            return Err(CgrcConfError::in_line(CgrcConfErrorKind::CgrcErrorIncludeCycle(cycle), name, 0, width));
+        }
+
+        let local_path = name.contains(std::path::MAIN_SEPARATOR) && Path::new(name).exists();
+        let content = match CGRCConfManager::load_conf(&name.to_string(), local_path) {
+            None => return Err(CgrcConfError::in_line(CgrcConfErrorKind::CgrcErrorIncludeNotFound(name.to_string()), name, 0, width)),
+            Some(v) => v
+        };
+
+        let mut included_stack = stack.to_vec();
+        included_stack.push(name.to_string());
+        Self::parse_conf_lines_included(BufReader::new(Cursor::new(content)), &included_stack)
+    }
+
+    ///
+    /// Moves an error built on a portion of the line, starting at offset,
+    /// so that it points to the right position in the whole line.
+    /// 
+    fn offset_error(e: CgrcConfError, line: &str, offset: usize) -> CgrcConfError {
+        if e.line_number.is_some() {
+            return e;
+        }
+
+        CgrcConfError {
+            line: Some(line.to_string()),
+            column: e.column + line[..offset].chars().count(),
+            ..e
+        }
+    }
+
+    ///
+    /// Builds the error for a regex starting at offset in the line, pointing
+    /// at the position reported by the regex parser when available.
+    /// 
+    fn regex_error(e: fancy_regex::Error, line: &str, offset: usize) -> CgrcConfError {
+        let pattern = &line[offset..];
+        let (position, width) = match &e {
+            fancy_regex::Error::ParseError(position, _) => (*position, 1),
+            _ => (0, pattern.chars().count())
+        };
+        let column = line[..offset + position.min(pattern.len())].chars().count();
+        CgrcConfError::in_line(CgrcConfErrorKind::CgrcErrorInvalidRegex(Box::new(e)), line, column, width)
+    }
+
+    ///
+    /// Parses the colors of a colours= line. Also returns the tokens that
+    /// are not recognized, which are otherwise ignored.
+    /// 
+    pub fn parse_colors(line: &str) -> (Vec<CgrcColorItem>, Vec<String>) {
         let mut items: Vec<CgrcColorItem> = vec![];
-        let line_tokens = line.split(",");
+        let mut unknown: Vec<String> = vec![];
+        let line_tokens = line.split(',');
         for line_token in line_tokens {
-            let options = line_token.split(" ");
+            let options = line_token.split(' ');
             let mut attrs: HashSet<CgrcAttrib> = HashSet::new();
             let mut forg = LcLogColor::LcForgColDefault;
             let mut back: LcBackColor = LcBackColor::LcBackColDefault;
+            let mut forg_ext: Option<CgrcExtColor> = None;
+            let mut back_ext: Option<CgrcExtColor> = None;
             for option in options {
                 let lower_option = option.to_lowercase();
                 if COLORS_ATTRS.contains_key(option) {
-                    attrs.insert(COLORS_ATTRS.get(&lower_option).unwrap().clone());
+                    attrs.insert(*COLORS_ATTRS.get(&lower_option).unwrap());
                     continue;
                 }
                 if COLORS_BACK.contains_key(option) {
-                    back = COLORS_BACK.get(&lower_option).unwrap().clone();
-                    log::info!("Found color: {:?}", back);
+                    back = *COLORS_BACK.get(&lower_option).unwrap();
+                    back_ext = None;
                     continue;
                 }
                 if COLORS_FORG.contains_key(option) {
-                    forg = COLORS_FORG.get(&lower_option).unwrap().clone();
+                    forg = *COLORS_FORG.get(&lower_option).unwrap();
+                    forg_ext = None;
+                    continue;
+                }
+                if let Some(color) = lower_option.strip_prefix("on_").and_then(CgrcExtColor::parse) {
+                    back_ext = Some(color);
+                    continue;
+                }
+                if let Some(color) = CgrcExtColor::parse(&lower_option) {
+                    forg_ext = Some(color);
                     continue;
                 }
+                if !option.is_empty() {
+                    unknown.push(option.to_string());
+                }
             }
 
-            let item = CgrcColorItem::new(attrs, forg, back);
+            let item = CgrcColorItem::new_ext(attrs, forg, back, forg_ext, back_ext);
             items.push(item);
         }
 
-        items
+        (items, unknown)
+    }
+
+    ///
+    /// Replaces the matches with the expanded templates. The colors of the
+    /// untouched text are preserved, while each replacement takes the color
+    /// of the text it replaces. Returns the new line and the matches remapped
+    /// on it; only the whole match is kept, as groups do not survive the
+    /// replacement. The escape sequences of the input are moved with the
+    /// text around them.
+    /// 
+    fn replace_matches(line: &str,
+                       spans: &mut Vec<CgrcColorSpan>,
+                       controls: &mut [(usize, String)],
+                       regex_matches: &[Vec<Option<Range<usize>>>],
+                       replacements: &[String]) -> (String, Vec<CgrcMatch>) {
+        let mut new_line = String::new();
+        let mut new_matches: Vec<CgrcMatch> = vec![];
+        let mut edits: Vec<(Range<usize>, Range<usize>)> = vec![];
+        let mut last_index = 0;
+        for (regex_match, replacement) in regex_matches.iter().zip(replacements) {
+            let whole_match = regex_match[0].clone().unwrap();
+            new_line += &line[last_index..whole_match.start];
+            let new_range = new_line.len()..new_line.len() + replacement.len();
+            new_matches.push(vec![Some(new_range.clone())]);
+            edits.push((whole_match.clone(), new_range));
+            new_line += replacement;
+            last_index = whole_match.end;
+        }
+
+        new_line += &line[last_index..];
+        for span in spans.iter_mut() {
+            span.range = Self::map_position(span.range.start, &edits, false)..Self::map_position(span.range.end, &edits, true);
+        }
+        spans.retain(|s| !s.range.is_empty());
+        for (position, _) in controls.iter_mut() {
+            *position = Self::map_position(*position, &edits, false);
+        }
+        (new_line, new_matches)
+    }
+
+    ///
+    /// Maps a position of a span onto the line rewritten with the edits. A
+    /// span including the start of a replaced text includes the whole
+    /// replacement, while the rest of the replaced text is dropped.
+    /// 
+    fn map_position(position: usize, edits: &[(Range<usize>, Range<usize>)], is_end: bool) -> usize {
+        let mut delta: isize = 0;
+        for (old, new) in edits {
+            if position < old.start || (is_end && position == old.start) {
+                break;
+            }
+            if position == old.start {
+                return new.start;
+            }
+            if position < old.end || (is_end && position == old.end) {
+                return new.end;
+            }
+            delta = new.end as isize - old.end as isize;
+        }
+
+        (position as isize + delta) as usize
+    }
+
+    ///
+    /// Returns the end of the escape sequence starting at start: a CSI
+    /// sequence, a string like OSC, terminated by BEL or ST, or an escape
+    /// followed by intermediate bytes and a final byte. Unterminated
+    /// strings extend to the end of the line.
+    /// 
+    fn escape_end(line: &str, start: usize) -> usize {
+        let bytes = line.as_bytes();
+        let mut end = start + 1;
+        match bytes.get(end) {
+            None => end,
+            Some(b'[') => {
+                end += 1;
+                while end < bytes.len() && (0x20..=0x3f).contains(&bytes[end]) {
+                    end += 1;
+                }
+                match bytes.get(end) {
+                    Some(0x40..=0x7e) => end + 1,
+                    _ => end
+                }
+            },
+            Some(b']' | b'P' | b'X' | b'^' | b'_') => {
+                end += 1;
+                while end < bytes.len() {
+                    match (bytes[end], bytes.get(end + 1)) {
+                        (0x07, _) => return end + 1,
+                        (0x1b, Some(b'\\')) => return end + 2,
+                        _ => end += 1
+                    }
+                }
+                end
+            },
+            Some(_) => {
+                while end < bytes.len() && (0x20..=0x2f).contains(&bytes[end]) {
+                    end += 1;
+                }
+                match bytes.get(end) {
+                    Some(0x30..=0x7e) => end + 1,
+                    _ => end
+                }
+            }
+        }
+    }
+
+    ///
+    /// Splits the line in segments with the color resulting from the spans
+    /// covering them. Spans are composed in the order they were added.
+    /// 
+    fn resolve_spans<'a>(spans: &[CgrcColorSpan<'a>], length: usize) -> CgrcSegments<'a> {
+        let mut bounds: Vec<(usize, bool, usize)> = spans.iter()
+            .enumerate()
+            .flat_map(|(i, span)| [(span.range.start, true, i), (span.range.end, false, i)])
+            .collect();
+        bounds.sort_unstable();
+
+        let mut segments: CgrcSegments = vec![];
+        let mut active: Vec<usize> = vec![];
+        let mut position = 0;
+        for (bound, is_start, index) in bounds {
+            if bound > position {
+                let color = match active.as_slice() {
+                    [] => None,
+                    [single] => Some(Cow::Borrowed(spans[*single].color)),
+                    [first, rest @ ..] => Some(Cow::Owned(rest.iter().fold(
+                        spans[*first].color.clone(),
+                        |color, i| color.compose(spans[*i].color)
+                    )))
+                };
+                segments.push((position..bound, color));
+                position = bound;
+            }
+
+            match (is_start, active.binary_search(&index)) {
+                (true, Err(at)) => active.insert(at, index),
+                (false, Ok(at)) => { active.remove(at); },
+                _ => {}
+            }
+        }
+
+        if position < length {
+            segments.push((position..length, None));
+        }
+
+        segments
     }
 
     ///
-    /// Parses the line.
+    /// Splits a line in the visible text and the escape sequences it
+    /// contains. The colors set by SGR sequences start from the ones left
+    /// by the previous line, kept in the state, and are adapted to the
+    /// depth provided. Unless sanitize is off, the other escape sequences
+    /// and the control characters, except tabs, are removed or escaped in
+    /// the text.
     /// 
-    pub fn parse_log_line(conf_items: &Vec<CgrcConfItem>, in_line: &String, debug: bool) -> Option<String> {
-        let in_line_length = in_line.len();
-        if in_line_length <= 0 {
-            return None;
+    pub fn parse_ansi_line(line: &str, depth: CgrcColorDepth, sanitize: CgrcSanitizeMode, state: &mut CgrcParserState) -> CgrcAnsiLine {
+        // Bytes 0x80 to 0x9f that are not valid UTF-8 are controls as well.
+        let is_special = |index: usize, c: char| match sanitize {
+            CgrcSanitizeMode::CgrcSanitizeOff => c == '\x1b',
+            _ => (c.is_control() && c != '\t') || (is_raw_byte(c) && is_raw_control(line, index))
+        };
+        let find_special = |from: usize| line[from..].char_indices()
+            .find(|(i, c)| is_special(from + i, *c))
+            .map(|(i, _)| i);
+        if find_special(0).is_none() {
+            let mut ansi_line = CgrcAnsiLine::plain(line);
+            if let Some(color) = state.input_color.as_ref().filter(|_| !line.is_empty()) {
+                ansi_line.colors.push((0..line.len(), color.clone()));
+            }
+            return ansi_line;
+        }
+
+        let mut ansi_line = CgrcAnsiLine {
+            text: String::with_capacity(line.len()),
+            colors: vec![],
+            controls: vec![],
+        };
+        let mut color = match state.input_color.take() {
+            Some(v) => v,
+            None => {
+                let mut color = CgrcColorItem::new(HashSet::new(), LcLogColor::LcForgColDefault, LcBackColor::LcBackColDefault);
+                color.set_color_depth(depth);
+                color
+            }
+        };
+        let mut color_start = 0;
+        let mut position = 0;
+        while let Some(offset) = find_special(position) {
+            let start = position + offset;
+            let end = match line[start..].starts_with('\x1b') {
+                true => Self::escape_end(line, start),
+                false => start + line[start..].chars().next().map_or(1, |c| c.len_utf8())
+            };
+            ansi_line.text += &line[position..start];
+            position = end;
+
+            let sequence = &line[start..end];
+            let params = sequence.strip_prefix("\x1b[")
+                .and_then(|s| s.strip_suffix('m'))
+                .filter(|p| p.bytes().all(|b| b.is_ascii_digit() || b == b';' || b == b':'));
+            let params = match (params, sanitize) {
+                (Some(v), _) => v,
+                (None, CgrcSanitizeMode::CgrcSanitizeOff) => {
+                    ansi_line.controls.push((ansi_line.text.len(), sequence.to_string()));
+                    continue;
+                },
+                (None, CgrcSanitizeMode::CgrcSanitizeRemove) => continue,
+                (None, CgrcSanitizeMode::CgrcSanitizeEscape) => {
+                    ansi_line.text += &Self::escape_controls(sequence);
+                    continue;
+                }
+            };
+
+            let new_color = color.apply_sgr(params);
+            if new_color.escape_seq == color.escape_seq {
+                continue;
+            }
+            if !color.is_plain() && ansi_line.text.len() > color_start {
+                ansi_line.colors.push((color_start..ansi_line.text.len(), color));
+            }
+            color = new_color;
+            color_start = ansi_line.text.len();
+        }
+
+        ansi_line.text += &line[position..];
+        if !color.is_plain() {
+            if ansi_line.text.len() > color_start {
+                ansi_line.colors.push((color_start..ansi_line.text.len(), color.clone()));
+            }
+            state.input_color = Some(color);
+        }
+
+        ansi_line
+    }
+
+    ///
+    /// Writes the control characters in caret notation, e.g. ^[ for the
+    /// escape character and ^? for delete, and the C1 controls as \x9b.
+    /// Bytes that are not valid UTF-8 are written in the same way.
+    /// 
+    fn escape_controls(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len() + 4);
+        for c in text.chars() {
+            if let Some(byte) = raw_byte(c) {
+                escaped += &format!("\\x{:02x}", byte);
+                continue;
+            }
+
+            match c as u32 {
+                0x00..=0x1f | 0x7f if c != '\t' => {
+                    escaped.push('^');
+                    escaped.push((c as u8 ^ 0x40) as char);
+                },
+                0x80..=0x9f => escaped += &format!("\\x{:02x}", c as u32),
+                _ => escaped.push(c)
+            }
         }
 
-        let mut char_colors: Vec<*const CgrcColorItem> = vec![ptr::null(); in_line_length];
-        let mut stop_processing = false;
-        for conf_item in conf_items {
+        escaped
+    }
+
+    ///
+    /// Parses the line. The state is carried across lines to support the
+    /// block, unblock and previous count modes. The colors already in the
+    /// line are kept below the ones of the rules.
+    /// 
+    pub fn parse_log_line(conf_items: &[CgrcConfItem], state: &mut CgrcParserState, in_line: &str, debug: bool) -> Option<String> {
+        let input = Self::parse_ansi_line(in_line, CgrcColorDepth::CgrcDepthTrueColor, CgrcSanitizeMode::CgrcSanitizeOff, state);
+        Self::process_log_line(conf_items, None, state, input, CgrcAnsiPolicy::CgrcAnsiOverride, &CgrcAnsiRenderer {}, debug, true)
+    }
+
+    ///
+    /// Applies the rules to the line without coloring it: skip rules still
+    /// filter the line and replace rules still rewrite it.
+    /// 
+    pub fn filter_log_line(conf_items: &[CgrcConfItem], state: &mut CgrcParserState, in_line: &str, debug: bool) -> Option<String> {
+        let input = Self::parse_ansi_line(in_line, CgrcColorDepth::CgrcDepthTrueColor, CgrcSanitizeMode::CgrcSanitizeOff, state);
+        Self::process_log_line(conf_items, None, state, input, CgrcAnsiPolicy::CgrcAnsiOverride, &CgrcAnsiRenderer {}, debug, false)
+    }
+
+    ///
+    /// Applies the rules to the line and writes the resulting segments with
+    /// the renderer.
+    /// 
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn process_log_line(conf_items: &[CgrcConfItem],
+                                   prefilter: Option<&CgrcRegexSet>,
+                                   state: &mut CgrcParserState,
+                                   input: CgrcAnsiLine,
+                                   ansi: CgrcAnsiPolicy,
+                                   renderer: &dyn CgrcRenderer,
+                                   debug: bool,
+                                   colorize: bool) -> Option<String> {
+        Self::style_log_line(conf_items, prefilter, state, input, ansi, debug, colorize,
+            |line, segments, controls| Self::render_segments(line, &segments, &controls, renderer))
+    }
+
+    ///
+    /// Applies the rules to the visible text of the line. The prefilter,
+    /// built on the same rules, allows to skip the rules that cannot match
+    /// the line. The colors of the input are merged with the ones of the
+    /// rules according to the policy, also when the line is not colorized.
+    /// The resulting text, its segments and the escape sequences of the
+    /// input that are not colors are passed to output, unless the line is
+    /// skipped.
+    /// 
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn style_log_line<T>(conf_items: &[CgrcConfItem],
+                                    prefilter: Option<&CgrcRegexSet>,
+                                    state: &mut CgrcParserState,
+                                    input: CgrcAnsiLine,
+                                    ansi: CgrcAnsiPolicy,
+                                    debug: bool,
+                                    colorize: bool,
+                                    output: impl FnOnce(String, CgrcSegments, Vec<(usize, String)>) -> T) -> Option<T> {
+        let CgrcAnsiLine { text: mut line, colors: input_colors, mut controls } = input;
+
+        let input_colors = match ansi {
+            CgrcAnsiPolicy::CgrcAnsiStrip => vec![],
+            _ => input_colors
+        };
+
+        // Keep a local copy of the block color: the state may change while
+        // processing the line, but the spans refer to it.
+        let block_color = state.block_color.clone();
+        let mut spans: Vec<CgrcColorSpan> = input_colors.iter()
+            .map(|(range, color)| CgrcColorSpan { range: range.clone(), color, origin: CgrcSpanOrigin::CgrcSpanInput })
+            .collect();
+        if let (Some(color), true) = (&block_color, colorize) {
+            spans.push(CgrcColorSpan { range: 0..line.len(), color, origin: CgrcSpanOrigin::CgrcSpanBlock });
+        }
+        // Rules stop only the following rules of the same layer.
+        let mut stopped_layer: Option<usize> = None;
+        let mut prev_count_mode = &CgrcCountMode::CgrcCountMore;
+        let mut prev_colors: &Vec<CgrcColorItem> = &vec![];
+        // The matches of the prefilter are valid until the line is rewritten.
+        let mut set_matches = prefilter.map(|p| p.matches(&line));
+        for (index, conf_item) in conf_items.iter().enumerate() {
             if debug {
                 log::debug!("Testing conf: {:?}", conf_item);
             }
             
-            if stop_processing {
-                break;
+            if stopped_layer == Some(conf_item.layer) {
+                continue;
+            }
+
+            let mut count_mode = conf_item.count_mode.as_ref().unwrap_or(&CgrcCountMode::CgrcCountMore);
+            let mut colors = &conf_item.colors;
+            if count_mode == &CgrcCountMode::CgrcCountPrevious {
+                count_mode = prev_count_mode;
+                if colors.is_empty() {
+                    colors = prev_colors;
+                }
             }
+            prev_count_mode = count_mode;
+            prev_colors = colors;
 
-            let count_mode = conf_item.count_mode.as_ref().unwrap_or(&CgrcCountMode::CgrcCountMore);
+            // Without colors, only the rules changing the text or the flow
+            // of the processing are relevant.
+            if !colorize
+                && !conf_item.skip.unwrap_or(false)
+                && conf_item.replace.is_none()
+                && count_mode != &CgrcCountMode::CgrcCountStop
+                && count_mode != &CgrcCountMode::CgrcCountBlock
+                && count_mode != &CgrcCountMode::CgrcCountUnblock {
+                continue;
+            }
+
+            if let (Some(prefilter), Some(set_matches)) = (prefilter, &set_matches) {
+                if !prefilter.may_match(set_matches, index) {
+                    continue;
+                }
+            }
+
+            let single_match = matches!(count_mode,
+                CgrcCountMode::CgrcCountOnce | CgrcCountMode::CgrcCountBlock | CgrcCountMode::CgrcCountUnblock);
+            let skip = conf_item.skip.unwrap_or(false);
+            let limit = if single_match || skip { 1 } else { usize::MAX };
             let regex = conf_item.regex.as_ref().unwrap();
-            for regex_match in regex.captures_iter(&in_line) {
-                if let Ok(regex_match) = regex_match {
-                    if conf_item.skip.unwrap_or(false) {
-                        return None;
-                    }
-                    
-                    for i in 0..regex_match.len() {
-                        if i >= conf_item.colors.len() {
+            let (mut regex_matches, replacements) = regex.captures(&line, limit, conf_item.replace.as_deref());
+            if skip && !regex_matches.is_empty() {
+                return None;
+            }
+
+            if !replacements.is_empty() {
+                let (new_line, new_matches) = Self::replace_matches(&line, &mut spans, &mut controls, &regex_matches, &replacements);
+                if debug {
+                    log::debug!("Replaced: {:?} -> {:?}", line, new_line);
+                }
+                line = new_line;
+                regex_matches = new_matches;
+                set_matches = None;
+            }
+
+            for regex_match in regex_matches {
+                let whole_match = regex_match[0].clone().unwrap();
+                if !colorize {
+                    match count_mode {
+                        CgrcCountMode::CgrcCountStop => stopped_layer = Some(conf_item.layer),
+                        CgrcCountMode::CgrcCountBlock | CgrcCountMode::CgrcCountUnblock => {
+                            stopped_layer = Some(conf_item.layer);
                             break;
+                        },
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                if count_mode == &CgrcCountMode::CgrcCountBlock {
+                    if let Some(color) = colors.first() {
+                        if !color.attrs.contains(&CgrcAttrib::CgrcNone) && whole_match.start < line.len() {
+                            spans.push(CgrcColorSpan { range: whole_match.start..line.len(), color, origin: CgrcSpanOrigin::CgrcSpanRule });
                         }
-    
-                        let capture = match regex_match.get(i) {
-                            None => continue,
-                            Some(v) => v
-                        };
+                    }
+                }
+                
+                for (i, capture) in regex_match.into_iter().enumerate() {
+                    if i >= colors.len() {
+                        break;
+                    }
+
+                    let capture = match capture {
+                        None => continue,
+                        Some(v) => v
+                    };
+                    if debug {
+                        log::debug!("Captured: {:?}", &line[capture.clone()]);
+                    }
+                    if !colors[i].attrs.contains(&CgrcAttrib::CgrcNone) && !capture.is_empty() {
+                        spans.push(CgrcColorSpan { range: capture, color: &colors[i], origin: CgrcSpanOrigin::CgrcSpanRule });
                         if debug {
-                            log::debug!("Captured: {:?}", capture.as_str());
-                        }
-                        let from = capture.start();
-                        let to   = capture.end();
-                        for j in from..to {
-                            if !conf_item.colors[i].attrs.contains(&CgrcAttrib::CgrcNone) {
-                                char_colors[j] = &(conf_item.colors[i]);
-                                if debug {
-                                    log::warn!("Color: {:?}", conf_item.colors[i]);
-                                }
-                            }
+                            log::warn!("Color: {:?}", colors[i]);
                         }
                     }
+                }
 
-                    stop_processing = count_mode == &CgrcCountMode::CgrcCountStop;
-
-                    if count_mode == &CgrcCountMode::CgrcCountOnce {
-                        break;
-                    }
+                match count_mode {
+                    CgrcCountMode::CgrcCountStop => {
+                        stopped_layer = Some(conf_item.layer);
+                    },
+                    CgrcCountMode::CgrcCountBlock => {
+                        state.block_color = colors.first().cloned();
+                        stopped_layer = Some(conf_item.layer);
+                    },
+                    CgrcCountMode::CgrcCountUnblock => {
+                        state.block_color = None;
+                        for span in spans.iter_mut().filter(|s| s.origin == CgrcSpanOrigin::CgrcSpanBlock) {
+                            span.range.end = span.range.end.min(whole_match.end);
+                        }
+                        spans.retain(|s| !s.range.is_empty());
+                        stopped_layer = Some(conf_item.layer);
+                    },
+                    _ => {}
                 }
             }
-            
         }
 
-        let mut formatted_line = String::new();
-        let mut formatted_seq;
-        let mut last_color = char_colors[0];
-        let mut last_index = 0;
-        for i in 1..=in_line_length {
-            if i != in_line_length && char_colors[i] == last_color {
-                continue;
-            }
-            if last_color.is_null() {
-                formatted_seq = "\x1b[0m".to_string().to_string() + &in_line[last_index..i].to_owned();
-            }
-            else {
-                unsafe {
-                    formatted_seq = (*last_color).escape_seq.to_owned()
-                        + &in_line[last_index..i].to_owned()
-                        + "\x1b[0m".to_string().as_str();
-                }
-            }
+        // The colors of the input are drawn last, on top of the rules.
+        if ansi == CgrcAnsiPolicy::CgrcAnsiKeep {
+            spans.sort_by_key(|s| s.origin == CgrcSpanOrigin::CgrcSpanInput);
+        }
+
+        let segments = Self::resolve_spans(&spans, line.len());
+        Some(output(line, segments, controls))
+    }
 
-            formatted_line += &formatted_seq;
+    ///
+    /// Writes the segments of the line with the renderer. Lines with no
+    /// colors and no escape sequences are written as plain text. Lines
+    /// holding only escape sequences, like a clear screen, are written with
+    /// their sequences.
+    /// 
+    fn render_segments(line: String, segments: &CgrcSegments, controls: &[(usize, String)], renderer: &dyn CgrcRenderer) -> String {
+        if controls.is_empty() && segments.iter().all(|(_, color)| color.as_ref().is_none_or(|c| c.is_plain())) {
+            return renderer.plain(line);
+        }
 
-            if i != in_line_length {
-                last_index = i;
-                last_color = char_colors[i];
+        let mut formatted_line = String::with_capacity(line.len() + 16 * segments.len());
+        let mut current: Option<&CgrcColorItem> = None;
+        let mut controls = controls.iter().peekable();
+        for (range, color) in segments {
+            let color = color.as_deref().filter(|c| !c.is_plain());
+            match (color, current) {
+                (None, Some(current)) => renderer.close(&mut formatted_line, current),
+                // Adjacent segments looking the same are written as one.
+                (Some(color), Some(current)) if color.transition_seq(Some(current)).is_empty() => {},
+                (Some(color), _) => renderer.open(&mut formatted_line, color, current),
+                (None, None) => {}
+            }
+            let mut position = range.start;
+            while let Some((at, sequence)) = controls.next_if(|(at, _)| *at < range.end) {
+                renderer.text(&mut formatted_line, &line[position..*at]);
+                renderer.control(&mut formatted_line, sequence);
+                position = *at;
             }
+            renderer.text(&mut formatted_line, &line[position..range.end]);
+            current = color;
         }
 
-        formatted_line += &"\x1b[0;0m".to_string();
+        for (_, sequence) in controls {
+            renderer.control(&mut formatted_line, sequence);
+        }
+        if let Some(current) = current {
+            renderer.close(&mut formatted_line, current);
+        }
+
+        formatted_line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgrcdata::CgrcHtmlTheme;
+    use crate::cgrcinput::CgrcInputLine;
+    use crate::cgrcrenderer::CgrcHtmlRenderer;
+
+    fn format(rules: &str, line: &str) -> Option<String> {
+        let conf = CGRCParser::parse_conf_string(rules.to_string(), "test").unwrap();
+        let mut state = CgrcParserState::new();
+        CGRCParser::parse_log_line(&conf.items, &mut state, line, false)
+    }
+
+    #[test]
+    fn adjacent_segments_switch_color_without_reset() {
+        let rules = "regexp=a\ncolours=red\n-\nregexp=b\ncolours=green\n";
+        assert_eq!(format(rules, "ab").unwrap(), "\x1b[31ma\x1b[32mb\x1b[0m");
+    }
+
+    #[test]
+    fn nested_segments_emit_only_the_changes() {
+        let rules = "regexp=abc\ncolours=red\n-\nregexp=b\ncolours=bold\n";
+        assert_eq!(format(rules, "abc").unwrap(), "\x1b[31ma\x1b[1mb\x1b[0;31mc\x1b[0m");
+    }
+
+    #[test]
+    fn input_colors_carry_to_the_next_lines() {
+        let conf = CGRCParser::parse_conf_string("regexp=zzz\ncolours=red\n".to_string(), "test").unwrap();
+        let mut state = CgrcParserState::new();
+        let mut format = |line: &str| CGRCParser::parse_log_line(&conf.items, &mut state, line, false).unwrap();
+        assert_eq!(format("a\x1b[31m"), "a");
+        assert_eq!(format("b"), "\x1b[31mb\x1b[0m");
+        assert_eq!(format(""), "");
+        assert_eq!(format("c\x1b[0md"), "\x1b[31mc\x1b[0md");
+        assert_eq!(format("e"), "e");
+    }
+
+    #[test]
+    fn overlapping_spans_compose_in_order() {
+        let red = CgrcColorItem::new(HashSet::new(), LcLogColor::LcForgColRed, LcBackColor::LcBackColDefault);
+        let bold = CgrcColorItem::new(HashSet::from([CgrcAttrib::CgrcBright]), LcLogColor::LcForgColDefault, LcBackColor::LcBackColDefault);
+        let spans = [
+            CgrcColorSpan { range: 0..4, color: &red, origin: CgrcSpanOrigin::CgrcSpanRule },
+            CgrcColorSpan { range: 2..6, color: &bold, origin: CgrcSpanOrigin::CgrcSpanRule },
+        ];
+        let segments: Vec<(Range<usize>, Option<String>)> = CGRCParser::resolve_spans(&spans, 8)
+            .into_iter()
+            .map(|(range, color)| (range, color.map(|c| c.transition_seq(None))))
+            .collect();
+        assert_eq!(segments, vec![
+            (0..2, Some(String::from("\x1b[31m"))),
+            (2..4, Some(String::from("\x1b[31;1m"))),
+            (4..6, Some(String::from("\x1b[1m"))),
+            (6..8, None),
+        ]);
+    }
+
+    #[test]
+    fn positions_shift_with_the_replacements() {
+        let edits = [(2..4, 2..6)];
+        assert_eq!(CGRCParser::map_position(1, &edits, false), 1);
+        assert_eq!(CGRCParser::map_position(2, &edits, false), 2);
+        assert_eq!(CGRCParser::map_position(3, &edits, false), 6);
+        assert_eq!(CGRCParser::map_position(5, &edits, false), 7);
+        assert_eq!(CGRCParser::map_position(2, &edits, true), 2);
+        assert_eq!(CGRCParser::map_position(3, &edits, true), 6);
+        assert_eq!(CGRCParser::map_position(4, &edits, true), 6);
+    }
+
+    #[test]
+    fn replacements_move_spans_and_controls() {
+        let red = CgrcColorItem::new(HashSet::new(), LcLogColor::LcForgColRed, LcBackColor::LcBackColDefault);
+        let mut spans = vec![
+            CgrcColorSpan { range: 0..3, color: &red, origin: CgrcSpanOrigin::CgrcSpanInput },
+            CgrcColorSpan { range: 4..6, color: &red, origin: CgrcSpanOrigin::CgrcSpanRule },
+            CgrcColorSpan { range: 3..4, color: &red, origin: CgrcSpanOrigin::CgrcSpanRule },
+        ];
+        let mut controls = vec![(5, String::from("\x1b[K"))];
+        let (line, matches) = CGRCParser::replace_matches(
+            "abcdef", &mut spans, &mut controls, &[vec![Some(2..4)]], &[String::from("WXYZ")]);
+        assert_eq!(line, "abWXYZef");
+        assert_eq!(matches, vec![vec![Some(2..6)]]);
+        let ranges: Vec<Range<usize>> = spans.iter().map(|s| s.range.clone()).collect();
+        assert_eq!(ranges, vec![0..6, 6..8]);
+        assert_eq!(controls[0].0, 7);
+    }
+
+    #[test]
+    fn block_colors_lines_until_unblock() {
+        let rules = "regexp=BEGIN\ncolours=blue\ncount=block\n-\nregexp=END\ncolours=red\ncount=unblock\n";
+        let conf = CGRCParser::parse_conf_string(rules.to_string(), "test").unwrap();
+        let mut state = CgrcParserState::new();
+        let mut format = |line: &str| CGRCParser::parse_log_line(&conf.items, &mut state, line, false).unwrap();
+        assert_eq!(format("x BEGIN y"), "x \x1b[34mBEGIN y\x1b[0m");
+        assert_eq!(format("mid"), "\x1b[34mmid\x1b[0m");
+        assert_eq!(format("a END b"), "\x1b[34ma \x1b[31mEND\x1b[0m b");
+        assert_eq!(format("after"), "after");
+    }
+
+    #[test]
+    fn sanitize_neutralizes_invalid_c1_bytes() {
+        let line = CgrcInputLine::from_bytes(b"a\x9b31m \xf4\x8f\xbe\x80");
+        let escape = |sanitize| CGRCParser::parse_ansi_line(
+            &line.text, CgrcColorDepth::CgrcDepthTrueColor, sanitize, &mut CgrcParserState::new()).text;
+        assert_eq!(escape(CgrcSanitizeMode::CgrcSanitizeEscape), format!("a\\x9b31m {}", &line.text[9..]));
+        assert_eq!(escape(CgrcSanitizeMode::CgrcSanitizeRemove), format!("a31m {}", &line.text[9..]));
+        assert_eq!(escape(CgrcSanitizeMode::CgrcSanitizeOff), line.text);
+    }
+
+    #[test]
+    fn adjacent_segments_with_the_same_color_are_merged() {
+        let rules = "regexp=a\ncolours=red\n-\nregexp=b\ncolours=red\n";
+        let conf = CGRCParser::parse_conf_string(rules.to_string(), "test").unwrap();
+        let renderer = CgrcHtmlRenderer::new(CgrcHtmlTheme::CgrcHtmlDark);
+        let format = |line: &str| CGRCParser::process_log_line(
+            &conf.items,
+            None,
+            &mut CgrcParserState::new(),
+            CgrcAnsiLine::plain(line),
+            CgrcAnsiPolicy::CgrcAnsiOverride,
+            &renderer,
+            false,
+            true
+        ).unwrap();
+        assert_eq!(format("abc"), "<span style=\"color: #cd0000\">ab</span>c");
+        assert_eq!(CGRCParser::parse_log_line(&conf.items, &mut CgrcParserState::new(), "abc", false).unwrap(),
+                   "\x1b[31mab\x1b[0mc");
+    }
+
+    #[test]
+    fn inline_rules_keep_semicolons_in_the_regex() {
+        let conf = CGRCParser::parse_inline_rule("regexp=a;b; colours=red;count=stop", "-e").unwrap();
+        assert_eq!(conf.items.len(), 1);
+        assert_eq!(conf.items[0].regex.as_ref().unwrap().as_str(), "a;b");
+        assert_eq!(conf.items[0].count_mode, Some(CgrcCountMode::CgrcCountStop));
+    }
+
+    #[test]
+    fn inline_rules_report_unknown_colors_as_written() {
+        let rule = "regexp=İİİİ;colours=İbluex";
+        let error = CGRCParser::parse_inline_rule(rule, "-e").err().unwrap();
+        assert!(matches!(&error.kind, CgrcConfErrorKind::CgrcErrorUnknownColor(c) if c == "İbluex"));
+        assert_eq!(error.column, 20);
+        assert_eq!(error.line_number, Some(1));
+    }
+
+    #[test]
+    fn highlights_without_letters_after_the_colon_are_words() {
+        let item = CGRCParser::parse_highlight("12:30").unwrap();
+        assert_eq!(item.regex.as_ref().unwrap().captures("at 12:30", 1, None).0.len(), 1);
+        let item = CGRCParser::parse_highlight("error:bold blue").unwrap();
+        assert_eq!(item.regex.as_ref().unwrap().captures("an error", 1, None).0.len(), 1);
+        assert_eq!(item.colors.len(), 1);
+    }
+
+    #[test]
+    fn highlights_report_unknown_colors_at_their_column() {
+        let error = CGRCParser::parse_highlight("İİİ:bluex").unwrap_err();
+        assert!(matches!(&error.kind, CgrcConfErrorKind::CgrcErrorUnknownColor(c) if c == "bluex"));
+        assert_eq!(error.column, 4);
+        let error = CGRCParser::parse_highlight("İ:éx").unwrap_err();
+        assert!(matches!(&error.kind, CgrcConfErrorKind::CgrcErrorUnknownColor(c) if c == "éx"));
+        assert_eq!(error.column, 2);
+    }
+
+    fn write_conf(name: &str, content: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("cgrc-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn patterns(conf: &CgrcConf) -> Vec<&str> {
+        conf.items.iter().map(|i| i.regex.as_ref().unwrap().as_str()).collect()
+    }
+
+    #[test]
+    fn defines_are_expanded_in_regexes_and_defines() {
+        let rules = "define=NUM=\\d+\ndefine=PAIR=${NUM}:${NUM}\nregexp=at ${PAIR} $${PAIR}\ncolours=red\n";
+        let conf = CGRCParser::parse_conf_string(rules.to_string(), "test").unwrap();
+        assert_eq!(patterns(&conf), vec!["at \\d+:\\d+ ${PAIR}"]);
+    }
+
+    #[test]
+    fn undefined_names_are_reported() {
+        let rules = "regexp=a ${MISSING}\ncolours=red\n";
+        let error = CGRCParser::parse_conf_string(rules.to_string(), "test").err().unwrap();
+        assert!(matches!(&error.kind, CgrcConfErrorKind::CgrcErrorUndefinedName(n) if n == "MISSING"));
+        assert_eq!((error.line_number, error.column, error.width), (Some(1), 9, 10));
+    }
+
+    #[test]
+    fn includes_splice_rules_and_defines_in_place() {
+        let included = write_conf("splice", "define=WORD=\\w+\nregexp=b\ncolours=red\n");
+        let rules = format!("regexp=a\ncolours=red\ninclude={}\nregexp=${{WORD}}\ncolours=red\n", included);
+        let conf = CGRCParser::parse_conf_string(rules, "test").unwrap();
+        assert_eq!(patterns(&conf), vec!["a", "b", "\\w+"]);
+    }
+
+    #[test]
+    fn extends_overrides_base_rules_in_place() {
+        let base = write_conf("base", "desc=base\nregexp=a\ncolours=red\n-\nregexp=b\ncolours=red\n-\nregexp=c\ncolours=red\n");
+        let rules = format!("extends={}\nregexp=d\ncolours=blue\n-\nregexp=b\ncolours=green\n", base);
+        let conf = CGRCParser::parse_conf_string(rules, "test").unwrap();
+        assert_eq!(patterns(&conf), vec!["a", "b", "c", "d"]);
+        assert_eq!(conf.items[1].colors[0].forg, LcLogColor::LcForgColGreen);
+        assert_eq!(conf.description.as_deref(), Some("base"));
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let first = write_conf("first", "");
+        let second = write_conf("second", &format!("extends={}\n", first));
+        write_conf("first", &format!("include={}\n", second));
+        let error = CGRCParser::parse_conf_string(format!("include={}\n", first), "test").err().unwrap();
+        assert!(matches!(&error.kind, CgrcConfErrorKind::CgrcErrorIncludeCycle(c) if *c == ["test".to_string(), first.clone(), second, first]));
+    }
 
-        return Some(formatted_line);
+    #[test]
+    fn lines_without_colors_are_unchanged() {
+        let rules = "regexp=zzz\ncolours=red\n";
+        assert_eq!(format(rules, "abc").unwrap(), "abc");
+        assert_eq!(format(rules, "").unwrap(), "");
     }
 }
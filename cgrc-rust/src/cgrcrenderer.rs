@@ -0,0 +1,614 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::ops::Range;
+use crate::cgrcdata::{CgrcAttrib, CgrcColorItem, CgrcExtColor, CgrcHtmlTheme, CgrcStyledLine, LcBackColor, LcLogColor};
+use crate::cgrcinput::{decode_raw_bytes, is_raw_byte};
+
+///
+/// Writes the segments of a formatted line. The parser calls open for each
+/// segment with a color different from the current one, close when a
+/// colored segment is followed by one with no color or at the end of the
+/// line, and text and control for the content of the segments.
+///
+pub trait CgrcRenderer: Send + Sync {
+    ///
+    /// Returns the line to write when no part of it is colored.
+    ///
+    fn plain(&self, line: String) -> String;
+
+    ///
+    /// Starts a segment with the color provided, following a segment with
+    /// the current color, if any.
+    ///
+    fn open(&self, out: &mut String, color: &CgrcColorItem, current: Option<&CgrcColorItem>);
+
+    ///
+    /// Ends a segment with the current color.
+    ///
+    fn close(&self, out: &mut String, current: &CgrcColorItem);
+
+    ///
+    /// Writes text of the line.
+    ///
+    fn text(&self, out: &mut String, text: &str);
+
+    ///
+    /// Writes an escape sequence of the input that is not a color.
+    ///
+    fn control(&self, out: &mut String, sequence: &str);
+}
+
+///
+/// Renders the lines with SGR escape sequences for the terminal, emitting
+/// only the changes between adjacent segments.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgrcAnsiRenderer {}
+
+impl CgrcRenderer for CgrcAnsiRenderer {
+    fn plain(&self, line: String) -> String {
+        line
+    }
+
+    fn open(&self, out: &mut String, color: &CgrcColorItem, current: Option<&CgrcColorItem>) {
+        *out += &color.transition_seq(current);
+    }
+
+    fn close(&self, out: &mut String, _current: &CgrcColorItem) {
+        *out += "\x1b[0m";
+    }
+
+    fn text(&self, out: &mut String, text: &str) {
+        *out += text;
+    }
+
+    fn control(&self, out: &mut String, sequence: &str) {
+        *out += sequence;
+    }
+}
+
+///
+/// Renders the lines as HTML, with a span with inline styles for each
+/// colored segment. Escape sequences that are not colors are dropped.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgrcHtmlRenderer {
+    theme: CgrcHtmlTheme,
+}
+
+impl CgrcHtmlRenderer {
+    ///
+    /// Creates a renderer. The theme provides the colors swapped by the
+    /// reverse attribute when the text has the default colors.
+    ///
+    pub fn new(theme: CgrcHtmlTheme) -> CgrcHtmlRenderer {
+        CgrcHtmlRenderer { theme }
+    }
+
+    ///
+    /// Returns the beginning of a standalone page, to write before the lines.
+    ///
+    pub fn page_header(&self, title: &str) -> String {
+        let (foreground, background) = self.theme.colors();
+        let mut header = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>");
+        self.text(&mut header, title);
+        header += &format!("</title>\n<style>\nbody {{ margin: 0; color: {}; background-color: {}; }}\n\
+            pre {{ margin: 0; padding: 1em; font-family: ui-monospace, Menlo, Consolas, monospace; }}\n\
+            </style>\n</head>\n<body>\n<pre>", foreground, background);
+        header
+    }
+
+    ///
+    /// Returns the end of a standalone page, to write after the lines.
+    ///
+    pub fn page_footer(&self) -> String {
+        String::from("</pre>\n</body>\n</html>")
+    }
+
+    // Private portion
+    // ===============
+    fn style(&self, color: &CgrcColorItem) -> String {
+        let mut style: Vec<String> = vec![];
+        let (foreground, background) = resolve_colors(color, self.theme);
+        if let Some(c) = foreground {
+            style.push(format!("color: {}", c));
+        }
+        if let Some(c) = background {
+            style.push(format!("background-color: {}", c));
+        }
+
+        let mut decorations: Vec<&str> = vec![];
+        for attr in [
+            CgrcAttrib::CgrcBright,
+            CgrcAttrib::CgrcDim,
+            CgrcAttrib::CgrcItalic,
+            CgrcAttrib::CgrcUnderline,
+            CgrcAttrib::CgrcBlink,
+            CgrcAttrib::CgrcRapidBlink,
+            CgrcAttrib::CgrcHidden,
+            CgrcAttrib::CgrcStrikethrough
+        ] {
+            if !color.attrs.contains(&attr) {
+                continue;
+            }
+            match attr {
+                CgrcAttrib::CgrcBright => style.push(String::from("font-weight: bold")),
+                CgrcAttrib::CgrcDim => style.push(String::from("opacity: 0.6")),
+                CgrcAttrib::CgrcItalic => style.push(String::from("font-style: italic")),
+                CgrcAttrib::CgrcHidden => style.push(String::from("visibility: hidden")),
+                CgrcAttrib::CgrcUnderline => decorations.push("underline"),
+                CgrcAttrib::CgrcStrikethrough => decorations.push("line-through"),
+                _ if !decorations.contains(&"blink") => decorations.push("blink"),
+                _ => {}
+            }
+        }
+        if !decorations.is_empty() {
+            style.push(format!("text-decoration: {}", decorations.join(" ")));
+        }
+
+        style.join("; ")
+    }
+}
+
+impl CgrcRenderer for CgrcHtmlRenderer {
+    fn plain(&self, line: String) -> String {
+        let mut out = String::with_capacity(line.len());
+        self.text(&mut out, &line);
+        out
+    }
+
+    fn open(&self, out: &mut String, color: &CgrcColorItem, current: Option<&CgrcColorItem>) {
+        if current.is_some() {
+            *out += "</span>";
+        }
+        *out += "<span style=\"";
+        *out += &self.style(color);
+        *out += "\">";
+    }
+
+    fn close(&self, out: &mut String, _current: &CgrcColorItem) {
+        *out += "</span>";
+    }
+
+    fn text(&self, out: &mut String, text: &str) {
+        escape_markup(out, text);
+    }
+
+    fn control(&self, _out: &mut String, _sequence: &str) {}
+}
+
+///
+/// Renders an SVG picture of the lines, as they look in a terminal window.
+/// The lines are laid out in columns from their segments, so that the
+/// backgrounds match the text.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgrcSvgRenderer {
+    theme: CgrcHtmlTheme,
+}
+
+// Metrics of the picture, in pixels. The width of the characters is the
+// one of most monospace fonts at the size used.
+const SVG_FONT_SIZE: f32 = 14.0;
+const SVG_CHAR_WIDTH: f32 = 8.4;
+const SVG_LINE_HEIGHT: f32 = 18.0;
+const SVG_PADDING: f32 = 16.0;
+const SVG_TITLE_HEIGHT: f32 = 32.0;
+const SVG_TAB_WIDTH: usize = 8;
+
+// Markup of a line, columns with a background and number of columns.
+type CgrcSvgLine = (String, Vec<(Range<usize>, String)>, usize);
+
+impl CgrcSvgRenderer {
+    pub fn new(theme: CgrcHtmlTheme) -> CgrcSvgRenderer {
+        CgrcSvgRenderer { theme }
+    }
+
+    ///
+    /// Returns the picture of a terminal window with the title and the
+    /// lines formatted by a Colorizer with style_line().
+    ///
+    pub fn document(&self, title: &str, lines: &[CgrcStyledLine]) -> String {
+        let lines: Vec<CgrcSvgLine> = lines.iter()
+            .map(|l| self.layout_line(l))
+            .collect();
+        let columns = lines.iter().map(|(_, _, columns)| *columns).max().unwrap_or(0).max(40);
+        let width = SVG_CHAR_WIDTH * columns as f32 + 2.0 * SVG_PADDING;
+        let height = SVG_TITLE_HEIGHT + SVG_LINE_HEIGHT * lines.len() as f32 + 2.0 * SVG_PADDING;
+        let (foreground, background) = self.theme.colors();
+        let title_color = match self.theme {
+            CgrcHtmlTheme::CgrcHtmlDark => "#303030",
+            CgrcHtmlTheme::CgrcHtmlLight => "#e4e4e4"
+        };
+
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width:.1}\" height=\"{height:.1}\" \
+            viewBox=\"0 0 {width:.1} {height:.1}\" font-family=\"ui-monospace, Menlo, Consolas, 'DejaVu Sans Mono', monospace\" \
+            font-size=\"{SVG_FONT_SIZE}\">\n");
+        svg += &format!("<rect width=\"100%\" height=\"100%\" rx=\"8\" fill=\"{background}\"/>\n");
+        svg += &format!("<path d=\"M0 8a8 8 0 0 1 8-8h{inner:.1}a8 8 0 0 1 8 8v{bar:.1}h-{width:.1}z\" fill=\"{title_color}\"/>\n",
+            inner = width - 16.0, bar = SVG_TITLE_HEIGHT - 8.0);
+        for (i, color) in ["#ff5f56", "#ffbd2e", "#27c93f"].iter().enumerate() {
+            svg += &format!("<circle cx=\"{}\" cy=\"{}\" r=\"6\" fill=\"{}\"/>\n",
+                SVG_PADDING + 20.0 * i as f32, SVG_TITLE_HEIGHT / 2.0, color);
+        }
+        svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\" fill=\"{}\" text-anchor=\"middle\" opacity=\"0.7\">",
+            width / 2.0, SVG_TITLE_HEIGHT / 2.0 + SVG_FONT_SIZE / 3.0, foreground);
+        escape_markup(&mut svg, title);
+        svg += "</text>\n";
+
+        svg += &format!("<g fill=\"{foreground}\" xml:space=\"preserve\">\n");
+        for (index, (markup, backgrounds, _)) in lines.iter().enumerate() {
+            let top = SVG_TITLE_HEIGHT + SVG_PADDING + SVG_LINE_HEIGHT * index as f32;
+            for (range, color) in backgrounds {
+                svg += &format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"/>\n",
+                    Self::column_x(range.start),
+                    top,
+                    SVG_CHAR_WIDTH * range.len() as f32,
+                    SVG_LINE_HEIGHT,
+                    color);
+            }
+            svg += &format!("<text y=\"{:.1}\">{}</text>\n", top + SVG_LINE_HEIGHT * 0.75, markup);
+        }
+        svg += "</g>\n</svg>\n";
+        svg
+    }
+
+    // Private portion
+    // ===============
+    fn attributes(&self, color: &CgrcColorItem, background: &mut Option<String>) -> String {
+        let mut attributes = String::new();
+        let (foreground, back) = resolve_colors(color, self.theme);
+        *background = back;
+        if let Some(c) = foreground {
+            attributes += &format!(" fill=\"{}\"", c);
+        }
+        if color.attrs.contains(&CgrcAttrib::CgrcBright) {
+            attributes += " font-weight=\"bold\"";
+        }
+        if color.attrs.contains(&CgrcAttrib::CgrcItalic) {
+            attributes += " font-style=\"italic\"";
+        }
+        if color.attrs.contains(&CgrcAttrib::CgrcHidden) {
+            attributes += " fill-opacity=\"0\"";
+        }
+        else if color.attrs.contains(&CgrcAttrib::CgrcDim) {
+            attributes += " fill-opacity=\"0.6\"";
+        }
+        let decorations: Vec<&str> = [
+            (CgrcAttrib::CgrcUnderline, "underline"),
+            (CgrcAttrib::CgrcStrikethrough, "line-through")
+        ].iter()
+            .filter(|(attr, _)| color.attrs.contains(attr))
+            .map(|(_, decoration)| *decoration)
+            .collect();
+        if !decorations.is_empty() {
+            attributes += &format!(" text-decoration=\"{}\"", decorations.join(" "));
+        }
+
+        attributes
+    }
+
+    ///
+    /// Lays out the segments of a line in columns, expanding the tabs. Each
+    /// segment is placed at its column, so that wide characters or fonts
+    /// with a different width do not move the following ones away from
+    /// their backgrounds.
+    ///
+    fn layout_line(&self, line: &CgrcStyledLine) -> CgrcSvgLine {
+        let mut markup = String::with_capacity(line.text.len());
+        let mut backgrounds: Vec<(Range<usize>, String)> = vec![];
+        let mut column = 0;
+        for (range, color) in &line.segments {
+            let start = column;
+            let mut text = String::with_capacity(range.len());
+            for c in decode_raw_bytes(&line.text[range.clone()]).chars() {
+                match c {
+                    '\t' => {
+                        let spaces = SVG_TAB_WIDTH - column % SVG_TAB_WIDTH;
+                        text += &" ".repeat(spaces);
+                        column += spaces;
+                    },
+                    c => {
+                        text.push(c);
+                        column += display_width(c);
+                    }
+                }
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let mut background: Option<String> = None;
+            let attributes = match color {
+                None => String::new(),
+                Some(color) => self.attributes(color, &mut background)
+            };
+            if let Some(background) = background {
+                backgrounds.push((start..column, background));
+            }
+            markup += &format!("<tspan x=\"{:.1}\"{}>", Self::column_x(start), attributes);
+            escape_decoded_markup(&mut markup, &text);
+            markup += "</tspan>";
+        }
+
+        (markup, backgrounds, column)
+    }
+
+    fn column_x(column: usize) -> f32 {
+        SVG_PADDING + SVG_CHAR_WIDTH * column as f32
+    }
+}
+
+// Private portion
+// ===============
+
+///
+/// Returns the CSS colors of the text and of the background, None for the
+/// default ones. Reverse swaps them, using the colors of the theme in
+/// place of the default ones.
+///
+fn resolve_colors(color: &CgrcColorItem, theme: CgrcHtmlTheme) -> (Option<String>, Option<String>) {
+    let mut foreground = match (&color.forg_ext, color.forg) {
+        (Some(c), _) => Some(css_color(c.to_rgb())),
+        (None, LcLogColor::LcForgColDefault) => None,
+        (None, c) => Some(css_color(ansi16_rgb(c as u8 - 30)))
+    };
+    let mut background = match (&color.back_ext, color.back) {
+        (Some(c), _) => Some(css_color(c.to_rgb())),
+        (None, LcBackColor::LcBackColDefault) => None,
+        (None, c) => Some(css_color(ansi16_rgb(c as u8 - 40)))
+    };
+    if color.attrs.contains(&CgrcAttrib::CgrcReverse) {
+        let (default_foreground, default_background) = theme.colors();
+        std::mem::swap(&mut foreground, &mut background);
+        foreground = foreground.or(Some(default_background.to_string()));
+        background = background.or(Some(default_foreground.to_string()));
+    }
+
+    (foreground, background)
+}
+
+///
+/// Maps the index of a color code, where 60 and more are the bright
+/// colors, to the 16 colors palette.
+///
+fn ansi16_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        60.. => CgrcExtColor::CgrcExtIndexed(index - 60 + 8).to_rgb(),
+        _ => CgrcExtColor::CgrcExtIndexed(index).to_rgb()
+    }
+}
+
+fn css_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+///
+/// Escapes the text for HTML and XML. Bytes of the input that are not
+/// valid UTF-8 become replacement characters and control characters,
+/// except tabs, are dropped.
+///
+fn escape_markup(out: &mut String, text: &str) {
+    escape_decoded_markup(out, &decode_raw_bytes(text));
+}
+
+///
+/// Escapes text whose raw bytes are already decoded.
+///
+fn escape_decoded_markup(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => *out += "&amp;",
+            '<' => *out += "&lt;",
+            '>' => *out += "&gt;",
+            '"' => *out += "&quot;",
+            '\'' => *out += "&#39;",
+            // Written as references, as the output goes through
+            // CgrcInputLine::restore_bytes().
+            c if is_raw_byte(c) => *out += &format!("&#x{:x};", c as u32),
+            c if c.is_control() && c != '\t' => {},
+            c => out.push(c)
+        }
+    }
+}
+
+///
+/// Returns the number of columns taken by the character in a terminal:
+/// two for the wide characters of East Asian scripts and most emoji, none
+/// for the combining and formatting characters. This is an approximation
+/// of wcwidth() covering the common ranges.
+///
+fn display_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036f
+        | 0x0483..=0x0489
+        | 0x0591..=0x05bd
+        | 0x0610..=0x061a
+        | 0x064b..=0x065f
+        | 0x1ab0..=0x1aff
+        | 0x1dc0..=0x1dff
+        | 0x200b..=0x200f
+        | 0x2028..=0x202e
+        | 0x2060..=0x2064
+        | 0x20d0..=0x20ff
+        | 0xfe00..=0xfe0f
+        | 0xfe20..=0xfe2f
+        | 0xfeff
+        | 0xe0100..=0xe01ef => 0,
+        _ if c.is_control() => 0,
+        0x1100..=0x115f
+        | 0x231a..=0x231b
+        | 0x2329..=0x232a
+        | 0x23e9..=0x23ec
+        | 0x23f0
+        | 0x23f3
+        | 0x25fd..=0x25fe
+        | 0x2614..=0x2615
+        | 0x2648..=0x2653
+        | 0x267f
+        | 0x2693
+        | 0x26a1
+        | 0x26aa..=0x26ab
+        | 0x26bd..=0x26be
+        | 0x26c4..=0x26c5
+        | 0x26ce
+        | 0x26d4
+        | 0x26ea
+        | 0x26f2..=0x26f3
+        | 0x26f5
+        | 0x26fa
+        | 0x26fd
+        | 0x2705
+        | 0x270a..=0x270b
+        | 0x2728
+        | 0x274c
+        | 0x274e
+        | 0x2753..=0x2755
+        | 0x2757
+        | 0x2795..=0x2797
+        | 0x27b0
+        | 0x27bf
+        | 0x2b1b..=0x2b1c
+        | 0x2b50
+        | 0x2b55
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xa960..=0xa97f
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe10..=0xfe19
+        | 0xfe30..=0xfe6f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f004
+        | 0x1f0cf
+        | 0x1f18e
+        | 0x1f191..=0x1f19a
+        | 0x1f200..=0x1f251
+        | 0x1f300..=0x1f320
+        | 0x1f32d..=0x1f335
+        | 0x1f337..=0x1f37c
+        | 0x1f37e..=0x1f393
+        | 0x1f3a0..=0x1f3ca
+        | 0x1f3cf..=0x1f3d3
+        | 0x1f3e0..=0x1f3f0
+        | 0x1f3f4
+        | 0x1f3f8..=0x1f43e
+        | 0x1f440
+        | 0x1f442..=0x1f4fc
+        | 0x1f4ff..=0x1f53d
+        | 0x1f54b..=0x1f54e
+        | 0x1f550..=0x1f567
+        | 0x1f57a
+        | 0x1f595..=0x1f596
+        | 0x1f5a4
+        | 0x1f5fb..=0x1f64f
+        | 0x1f680..=0x1f6c5
+        | 0x1f6cc
+        | 0x1f6d0..=0x1f6d2
+        | 0x1f6d5..=0x1f6d7
+        | 0x1f6eb..=0x1f6ec
+        | 0x1f6f4..=0x1f6fc
+        | 0x1f7e0..=0x1f7eb
+        | 0x1f90c..=0x1f93a
+        | 0x1f93c..=0x1f945
+        | 0x1f947..=0x1f9ff
+        | 0x1fa70..=0x1faff
+        | 0x20000..=0x2fffd
+        | 0x30000..=0x3fffd => 2,
+        _ => 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use crate::cgrcinput::CgrcInputLine;
+
+    #[test]
+    fn display_width_counts_terminal_columns() {
+        assert_eq!("abc".chars().map(display_width).sum::<usize>(), 3);
+        assert_eq!("日本語".chars().map(display_width).sum::<usize>(), 6);
+        assert_eq!("e\u{301}".chars().map(display_width).sum::<usize>(), 1);
+        assert_eq!(display_width('🚀'), 2);
+    }
+
+    #[test]
+    fn svg_backgrounds_follow_wide_characters() {
+        let renderer = CgrcSvgRenderer::new(CgrcHtmlTheme::CgrcHtmlDark);
+        let on_red = CgrcColorItem::new(HashSet::new(), LcLogColor::LcForgColDefault, LcBackColor::LcBackColRed);
+        let line = CgrcStyledLine {
+            text: String::from("日本\tab"),
+            segments: vec![(0..6, None), (6..7, None), (7..9, Some(on_red))],
+        };
+        let (markup, backgrounds, columns) = renderer.layout_line(&line);
+        assert_eq!(columns, 10);
+        assert_eq!(backgrounds, vec![(8..10, String::from("#cd0000"))]);
+        assert_eq!(markup, "<tspan x=\"16.0\">日本</tspan><tspan x=\"49.6\">    </tspan><tspan x=\"83.2\">ab</tspan>");
+    }
+
+    fn html(theme: CgrcHtmlTheme, color: &CgrcColorItem, text: &str) -> String {
+        let renderer = CgrcHtmlRenderer::new(theme);
+        let mut out = String::new();
+        renderer.open(&mut out, color, None);
+        renderer.text(&mut out, text);
+        renderer.close(&mut out, color);
+        out
+    }
+
+    #[test]
+    fn html_escapes_markup_characters() {
+        let renderer = CgrcHtmlRenderer::default();
+        assert_eq!(renderer.plain(String::from("a & <b> \"c\" 'd'\x07\te")), "a &amp; &lt;b&gt; &quot;c&quot; &#39;d&#39;\te");
+        assert_eq!(renderer.page_header("<x>").lines().nth(4), Some("<title>&lt;x&gt;</title>"));
+    }
+
+    #[test]
+    fn html_writes_raw_bytes_as_characters() {
+        let renderer = CgrcHtmlRenderer::default();
+        let line = CgrcInputLine::from_bytes(b"a\xff b \xf4\x8f\xbe\x80");
+        let out = renderer.plain(line.text.clone());
+        assert_eq!(line.restore_bytes(&out), "a\u{fffd} b &#x10ff80;".as_bytes());
+    }
+
+    #[test]
+    fn html_styles_attributes_and_reverse_with_the_theme() {
+        let bold_red = CgrcColorItem::new(
+            HashSet::from([CgrcAttrib::CgrcBright, CgrcAttrib::CgrcUnderline]),
+            LcLogColor::LcForgColRed,
+            LcBackColor::LcBackColDefault
+        );
+        assert_eq!(html(CgrcHtmlTheme::CgrcHtmlDark, &bold_red, "a"),
+                   "<span style=\"color: #cd0000; font-weight: bold; text-decoration: underline\">a</span>");
+
+        let reverse = CgrcColorItem::new(HashSet::from([CgrcAttrib::CgrcReverse]), LcLogColor::LcForgColDefault, LcBackColor::LcBackColDefault);
+        assert_eq!(html(CgrcHtmlTheme::CgrcHtmlDark, &reverse, "a"),
+                   "<span style=\"color: #1c1c1c; background-color: #d0d0d0\">a</span>");
+        assert_eq!(html(CgrcHtmlTheme::CgrcHtmlLight, &reverse, "a"),
+                   "<span style=\"color: #ffffff; background-color: #1c1c1c\">a</span>");
+
+        let reverse_green = CgrcColorItem::new(HashSet::from([CgrcAttrib::CgrcReverse]), LcLogColor::LcForgColGreen, LcBackColor::LcBackColDefault);
+        assert_eq!(html(CgrcHtmlTheme::CgrcHtmlLight, &reverse_green, "a"),
+                   "<span style=\"color: #ffffff; background-color: #00cd00\">a</span>");
+    }
+}
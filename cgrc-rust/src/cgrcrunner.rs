@@ -0,0 +1,315 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use cgrc::cgrccolorizer::Colorizer;
+use cgrc::cgrcdata::CgrcColorMode;
+use cgrc::cgrcwriter::ColorizingWriter;
+
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static STDOUT_MASTER: AtomicI32 = AtomicI32::new(-1);
+static STDERR_MASTER: AtomicI32 = AtomicI32::new(-1);
+
+// Time to wait for the rest of a line before writing the part received,
+// like a prompt or a progress update.
+const PARTIAL_LINE_TIMEOUT_MS: libc::c_int = 50;
+
+const FORWARDED_SIGNALS: [libc::c_int; 7] = [
+    libc::SIGINT,
+    libc::SIGTERM,
+    libc::SIGHUP,
+    libc::SIGQUIT,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGCONT
+];
+
+pub struct CGRCRunner {}
+
+impl CGRCRunner {
+    ///
+    /// Runs the command with stdout and stderr attached to pseudo-terminals,
+    /// colorizing both streams. Returns the exit code of the child.
+    ///
+    pub fn run(colorizer: &Colorizer, command: &[String], color_mode: CgrcColorMode) -> i32 {
+        let (stdout_master, stdout_slave) = match Self::open_pty() {
+            Err(e) => {
+                log::error!("Failed to open pty: {e}");
+                return 1;
+            },
+            Ok(v) => v
+        };
+        let (stderr_master, stderr_slave) = match Self::open_pty() {
+            Err(e) => {
+                log::error!("Failed to open pty: {e}");
+                return 1;
+            },
+            Ok(v) => v
+        };
+
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..])
+            .stdout(Stdio::from(stdout_slave))
+            .stderr(Stdio::from(stderr_slave));
+        unsafe {
+            cmd.pre_exec(|| {
+                // Make the stdout pty the controlling terminal of the child,
+                // so that it behaves as if it was run interactively.
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        // The handlers are installed before spawning the child, so that a
+        // signal received meanwhile is forwarded once the child exists.
+        STDOUT_MASTER.store(stdout_master.as_raw_fd(), Ordering::SeqCst);
+        STDERR_MASTER.store(stderr_master.as_raw_fd(), Ordering::SeqCst);
+        let previous_handlers = Self::install_handlers();
+        let mut child = match cmd.spawn() {
+            Err(e) => {
+                Self::restore_handlers(previous_handlers);
+                STDOUT_MASTER.store(-1, Ordering::SeqCst);
+                STDERR_MASTER.store(-1, Ordering::SeqCst);
+                eprintln!("Failed to run {}: {}", command[0], e);
+                return 127;
+            },
+            Ok(c) => c
+        };
+
+        // Close the slaves in this process, otherwise the masters never
+        // report the end of the stream.
+        drop(cmd);
+
+        CHILD_PID.store(child.id() as i32, Ordering::SeqCst);
+        let signal = PENDING_SIGNAL.swap(0, Ordering::SeqCst);
+        if signal != 0 {
+            unsafe {
+                libc::kill(child.id() as i32, signal);
+            }
+        }
+
+        let mut stdout_colorizer = colorizer.clone();
+        stdout_colorizer.set_colorize(color_mode.should_colorize(io::stdout().is_terminal()));
+        let mut stderr_colorizer = colorizer.clone();
+        stderr_colorizer.set_colorize(color_mode.should_colorize(io::stderr().is_terminal()));
+        let status = thread::scope(|scope| {
+            scope.spawn(|| {
+                Self::colorize_stream(stdout_colorizer, File::from(stdout_master), &STDOUT_MASTER, io::stdout())
+            });
+            scope.spawn(|| {
+                Self::colorize_stream(stderr_colorizer, File::from(stderr_master), &STDERR_MASTER, io::stderr())
+            });
+            child.wait()
+        });
+
+        CHILD_PID.store(0, Ordering::SeqCst);
+        Self::restore_handlers(previous_handlers);
+        match status {
+            Err(e) => {
+                log::error!("Failed to wait for {}: {}", command[0], e);
+                1
+            },
+            Ok(s) => match s.code() {
+                Some(code) => code,
+                None => 128 + s.signal().unwrap_or(0)
+            }
+        }
+    }
+
+    // Private portion
+    // ===============
+    ///
+    /// Installs the handlers forwarding the signals to the child and
+    /// resizing the ptys, returning the previous handlers.
+    ///
+    fn install_handlers() -> Vec<(libc::c_int, libc::sighandler_t)> {
+        let mut handlers: Vec<(libc::c_int, libc::sighandler_t)> = FORWARDED_SIGNALS.iter()
+            .map(|signal| (*signal, Self::forward_signal as *const () as libc::sighandler_t))
+            .collect();
+        handlers.push((libc::SIGTSTP, Self::stop as *const () as libc::sighandler_t));
+        handlers.push((libc::SIGWINCH, Self::resize_ptys as *const () as libc::sighandler_t));
+        handlers.into_iter()
+            .map(|(signal, handler)| (signal, unsafe { libc::signal(signal, handler) }))
+            .collect()
+    }
+
+    fn restore_handlers(handlers: Vec<(libc::c_int, libc::sighandler_t)>) {
+        for (signal, handler) in handlers {
+            unsafe {
+                libc::signal(signal, handler);
+            }
+        }
+        PENDING_SIGNAL.store(0, Ordering::SeqCst);
+    }
+
+    fn open_pty() -> io::Result<(OwnedFd, OwnedFd)> {
+        let mut master: RawFd = -1;
+        let mut slave: RawFd = -1;
+        let winsize = Self::terminal_size();
+        let winsize_ptr = match &winsize {
+            None => ptr::null(),
+            Some(v) => v as *const libc::winsize
+        };
+        unsafe {
+            if libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), winsize_ptr) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = OwnedFd::from_raw_fd(master);
+            let slave = OwnedFd::from_raw_fd(slave);
+
+            // Keep newlines as they are: a \r written by the child is then
+            // always a carriage return, like in progress bars.
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+                termios.c_oflag &= !libc::ONLCR;
+                libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+            }
+            Ok((master, slave))
+        }
+    }
+
+    fn terminal_size() -> Option<libc::winsize> {
+        let mut winsize = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0
+        };
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO, libc::STDIN_FILENO] {
+            if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsize) } == 0 {
+                return Some(winsize);
+            }
+        }
+
+        None
+    }
+
+    ///
+    /// Colorizes the output of the child line by line. Lines are split like
+    /// in ColorizingWriter, and a partial line is written once no more
+    /// output arrives for a while, so that prompts and progress updates
+    /// show up. The master descriptor is cleared before the file is closed,
+    /// so that a resize does not reach a closed or reused descriptor.
+    ///
+    fn colorize_stream<W: Write>(colorizer: Colorizer, mut master: File, master_fd: &AtomicI32, out: W) {
+        let mut writer = ColorizingWriter::new(out, colorizer);
+        let mut buffer = [0u8; 4096];
+        loop {
+            if !Self::wait_readable(&master, PARTIAL_LINE_TIMEOUT_MS) && writer.finish().is_err() {
+                break;
+            }
+
+            let size = match master.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Linux reports EIO once all the slaves are closed.
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                Err(e) => {
+                    log::error!("Failed to read from child: {e}");
+                    break;
+                }
+            };
+
+            if writer.write_all(&buffer[..size]).is_err() || writer.flush().is_err() {
+                break;
+            }
+        }
+
+        master_fd.store(-1, Ordering::SeqCst);
+        drop(master);
+        let _ = writer.finish();
+    }
+
+    fn wait_readable(master: &File, timeout_ms: libc::c_int) -> bool {
+        let mut poll_fd = libc::pollfd {
+            fd: master.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0
+        };
+        // Errors and hang ups are reported by the next read.
+        unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) != 0 }
+    }
+
+    ///
+    /// Forwards the signal to the child. A signal received before the child
+    /// is spawned is kept pending, and forwarded by whoever sees the child
+    /// first, this handler or run().
+    ///
+    extern "C" fn forward_signal(signal: libc::c_int) {
+        let mut pid = CHILD_PID.load(Ordering::SeqCst);
+        if pid <= 0 {
+            PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+            pid = CHILD_PID.load(Ordering::SeqCst);
+            if pid <= 0 || PENDING_SIGNAL.swap(0, Ordering::SeqCst) == 0 {
+                return;
+            }
+        }
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+
+    ///
+    /// Stops the child and then cgrc on Ctrl-Z. The child runs in its own
+    /// session, where SIGTSTP is discarded, so it is stopped with SIGSTOP.
+    /// The SIGCONT sent by the shell to resume cgrc is then forwarded.
+    ///
+    extern "C" fn stop(_signal: libc::c_int) {
+        let pid = CHILD_PID.load(Ordering::SeqCst);
+        unsafe {
+            if pid > 0 {
+                libc::kill(pid, libc::SIGSTOP);
+            }
+            libc::kill(libc::getpid(), libc::SIGSTOP);
+        }
+    }
+
+    extern "C" fn resize_ptys(_signal: libc::c_int) {
+        let mut winsize = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0
+        };
+        unsafe {
+            if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut winsize) != 0 {
+                return;
+            }
+            for master in [&STDOUT_MASTER, &STDERR_MASTER] {
+                let fd = master.load(Ordering::SeqCst);
+                if fd >= 0 {
+                    libc::ioctl(fd, libc::TIOCSWINSZ, &winsize);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,241 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::io::{self, Write};
+use crate::cgrccolorizer::Colorizer;
+use crate::cgrcinput::CgrcInputLine;
+
+///
+/// Writer colorizing the text written through it and forwarding it to the
+/// inner writer. Text is buffered until a line is complete. Lines can end
+/// with \n, \r\n or a lone \r, like in progress bars, and the terminator is
+/// forwarded unchanged. A final unterminated line is written by finish() or
+/// when the writer is dropped.
+///
+pub struct ColorizingWriter<W: Write> {
+    inner: W,
+    colorizer: Colorizer,
+    buffer: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> ColorizingWriter<W> {
+    pub fn new(inner: W, colorizer: Colorizer) -> ColorizingWriter<W> {
+        ColorizingWriter {
+            inner,
+            colorizer,
+            buffer: vec![],
+            pending: vec![],
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    ///
+    /// Writes the pending unterminated line, if any, and flushes the inner
+    /// writer.
+    ///
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.colorize_lines();
+        if !self.buffer.is_empty() {
+            let line = std::mem::take(&mut self.buffer);
+            self.colorize_line(&line, b"");
+        }
+
+        self.write_pending()?;
+        self.inner.flush()
+    }
+
+    // Private portion
+    // ===============
+    fn colorize_line(&mut self, line: &[u8], terminator: &[u8]) {
+        let line = CgrcInputLine::from_bytes(line);
+        if let Some(formatted) = self.colorizer.colorize_line(&line.text) {
+            self.pending.extend_from_slice(&line.restore_bytes(&formatted));
+            self.pending.extend_from_slice(terminator);
+        }
+    }
+
+    ///
+    /// Colorizes the complete lines in the buffer, moving their output to
+    /// the pending bytes. A \r at the end of the buffer is kept, as it may
+    /// be followed by \n in the next write.
+    ///
+    fn colorize_lines(&mut self) {
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut start = 0;
+        let mut index = 0;
+        while index < buffer.len() {
+            let terminator_len = match (buffer[index], buffer.get(index + 1)) {
+                (b'\n', _) => 1,
+                (b'\r', Some(b'\n')) => 2,
+                (b'\r', Some(_)) => 1,
+                _ => 0
+            };
+            if terminator_len == 0 {
+                index += 1;
+                continue;
+            }
+
+            let end = index + terminator_len;
+            self.colorize_line(&buffer[start..index], &buffer[index..end]);
+            start = end;
+            index = end;
+        }
+
+        self.buffer = buffer[start..].to_vec();
+    }
+
+    ///
+    /// Writes the pending bytes to the inner writer. The bytes written are
+    /// removed as they are accepted, so after an error the rest is written
+    /// by the next call, with no line colorized or written twice.
+    ///
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.inner.write(&self.pending) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => { self.pending.drain(..n); },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ColorizingWriter<W> {
+    ///
+    /// Output left pending by a failed write is written first, and its
+    /// error is returned before accepting new bytes. The bytes are accepted
+    /// once their lines are colorized, so an error writing them stays
+    /// pending and is returned by the next call, like in BufWriter.
+    ///
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        self.buffer.extend_from_slice(buf);
+        self.colorize_lines();
+        if let Err(e) = self.write_pending() {
+            log::debug!("Output left pending: {}", e);
+        }
+        Ok(buf.len())
+    }
+
+    ///
+    /// Flushes the inner writer. An incomplete line stays buffered, so that
+    /// it is colorized as a whole once complete.
+    ///
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for ColorizingWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgrcparser::CGRCParser;
+
+    fn colorizer() -> Colorizer {
+        let conf = CGRCParser::parse_conf_string("regexp=b\ncolours=red\n".to_string(), "test").unwrap();
+        Colorizer::new(conf)
+    }
+
+    ///
+    /// Writer accepting at most limit bytes per call and failing once when
+    /// fail_at bytes have been written.
+    ///
+    struct FlakyWriter {
+        data: Vec<u8>,
+        limit: usize,
+        fail_at: Option<usize>,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail_at.is_some_and(|at| self.data.len() >= at) {
+                self.fail_at = None;
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let len = buf.len().min(self.limit);
+            self.data.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn terminators_are_forwarded_unchanged() {
+        let mut out: Vec<u8> = vec![];
+        let mut writer = ColorizingWriter::new(&mut out, colorizer());
+        writer.write_all(b"ab\r\na").unwrap();
+        writer.write_all(b"b\r").unwrap();
+        assert_eq!(writer.get_ref().as_slice(), b"a\x1b[31mb\x1b[0m\r\n");
+        writer.write_all(b"cb\n").unwrap();
+        drop(writer);
+        assert_eq!(out, b"a\x1b[31mb\x1b[0m\r\na\x1b[31mb\x1b[0m\rc\x1b[31mb\x1b[0m\n");
+    }
+
+    #[test]
+    fn flush_keeps_unterminated_lines() {
+        let mut out: Vec<u8> = vec![];
+        let mut writer = ColorizingWriter::new(&mut out, colorizer());
+        writer.write_all(b"a\na").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.get_ref().as_slice(), b"a\n");
+        writer.write_all(b"b").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(writer.get_ref().as_slice(), b"a\na\x1b[31mb\x1b[0m");
+    }
+
+    #[test]
+    fn unterminated_lines_are_written_on_drop() {
+        let mut out: Vec<u8> = vec![];
+        let mut writer = ColorizingWriter::new(&mut out, colorizer());
+        writer.write_all(b"ab").unwrap();
+        drop(writer);
+        assert_eq!(out, b"a\x1b[31mb\x1b[0m");
+    }
+
+    #[test]
+    fn failed_writes_are_resumed_without_duplicates() {
+        let inner = FlakyWriter { data: vec![], limit: 3, fail_at: Some(3) };
+        let mut writer = ColorizingWriter::new(inner, colorizer());
+        writer.write_all(b"abc\n").unwrap();
+        assert_eq!(writer.get_ref().data, b"a\x1b[");
+        assert!(writer.flush().is_ok());
+        writer.write_all(b"d\n").unwrap();
+        assert_eq!(writer.get_ref().data, b"a\x1b[31mb\x1b[0mc\nd\n");
+    }
+}
@@ -0,0 +1,48 @@
+/*
+ * This file is part of cgrc.
+ *
+ * Copyright (c) 2023 Luca Carlon
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//!
+//! Configurable text formatter, compatible with grc configurations.
+//!
+//! The [`Colorizer`] applies the rules of a configuration to lines of text:
+//!
+//! ```no_run
+//! let mut colorizer = cgrc::Colorizer::load("prio").unwrap();
+//! if let Some(line) = colorizer.colorize_line("Error: disk full") {
+//!     println!("{line}");
+//! }
+//! ```
+//!
+
+pub mod cgrcconfmanager;
+pub mod cgrcconfstorage;
+pub mod cgrcdata;
+pub mod cgrcerror;
+pub mod cgrcinput;
+pub mod cgrcparser;
+pub mod cgrcrenderer;
+pub mod cgrccolorizer;
+pub mod cgrclogformatter;
+pub mod cgrcwriter;
+
+pub use cgrccolorizer::Colorizer;
+pub use cgrclogformatter::ColorizingFormatter;
+pub use cgrcdata::{CgrcColorDepth, CgrcColorMode, CgrcConf, CgrcConfItem};
+pub use cgrcerror::{CgrcConfError, CgrcConfErrorKind};
+pub use cgrcparser::CGRCParser;
+pub use cgrcwriter::ColorizingWriter;
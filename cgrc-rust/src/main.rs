@@ -16,26 +16,41 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-pub mod cgrcconfmanager;
-pub mod cgrcconfstorage;
-pub mod cgrcdata;
-pub mod cgrcparser;
-pub mod cgrccli;
-
-use std::io::{BufRead, stdin};
-use cgrcparser::CGRCParser;
-use cgrcconfmanager::CGRCConfManager;
-use cgrccli::Cli;
+mod cgrcchecker;
+mod cgrccli;
+mod cgrcrunner;
+
+use std::fs::{self, File};
+use std::io::{BufWriter, IsTerminal, Write, stdin, stdout};
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::thread;
+use std::process;
+use std::sync::Arc;
+use cgrc::cgrcparser::CGRCParser;
+use cgrc::cgrcconfmanager::CGRCConfManager;
+use cgrc::cgrcdata::{CgrcAnsiPolicy, CgrcColorDepth, CgrcColorMode, CgrcConf, CgrcOutputFormat, CgrcSanitizeMode, CgrcStyledLine};
+use cgrc::cgrcrenderer::{CgrcHtmlRenderer, CgrcSvgRenderer};
+use cgrc::cgrcinput::{CgrcInputLine, CgrcLineReader};
+use cgrc::Colorizer;
+use cgrcchecker::CGRCChecker;
+use cgrccli::{Cli, CliAction};
+use cgrcrunner::CGRCRunner;
 use clap::Parser;
 
 fn main() {
-    unsafe {
-        libc::signal(libc::SIGINT, libc::SIG_IGN);
-    }
-
     env_logger::init();
 
     let args = Cli::parse();
+    if let Some(CliAction::Check { conf_path, all, confs }) = &args.action {
+        if confs.is_empty() && !all {
+            println!("Missing argument");
+            return;
+        }
+
+        process::exit(CGRCChecker::run(confs, *conf_path, *all));
+    }
+
     if args.list_locations {
         println!("Locations on your system used by cgrc:");
         println!("\tSystem location: {}", CGRCConfManager::default_system_path());
@@ -64,47 +79,259 @@ fn main() {
         return;
     }
 
-    let args_conf = match args.conf {
-        None => {
+    let mut inline_conf = CgrcConf::new();
+    for expression in &args.expressions {
+        match CGRCParser::parse_inline_rule(expression, "-e") {
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            },
+            Ok(v) => inline_conf.append(v, false)
+        }
+    }
+    for highlight in &args.highlights {
+        match CGRCParser::parse_highlight(highlight) {
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            },
+            Ok(v) => inline_conf.items.push(v)
+        }
+    }
+
+    let args_confs = match args.confs.is_empty() {
+        false => args.confs.clone(),
+        true if !args.command.is_empty() => match CGRCConfManager::conf_for_command(&args.command) {
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            },
+            Ok(Some(conf)) => vec![conf],
+            Ok(None) => {
+                log::info!("No configuration maps to the command");
+                vec![]
+            }
+        },
+        true if !inline_conf.items.is_empty() || args.strip_ansi || args.sanitize != CgrcSanitizeMode::default() => vec![],
+        true => {
             println!("Missing argument");
             return;
-        },
-        Some(conf) => conf
+        }
     };
 
     let is_local_path = args.conf_path;
-    let conf_data = CGRCConfManager::load_conf(&args_conf, is_local_path);
-    if None == conf_data {
-        println!("Failed to find conf file: {0}", args_conf);
-        return;
+    let mut conf = CgrcConf::new();
+    for args_conf in &args_confs {
+        let conf_data = match CGRCConfManager::load_conf(args_conf, is_local_path) {
+            None => {
+                println!("Failed to find conf file: {0}", args_conf);
+                return;
+            },
+            Some(v) => v
+        };
+
+        match CGRCParser::parse_conf_string(conf_data, args_conf) {
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            },
+            Ok(v) => conf.append(v, args.stop_per_conf)
+        }
     }
 
-    let conf = CGRCParser::parse_conf_string(conf_data.unwrap());
+    // Inline rules always apply on top of the configurations.
+    conf.append(inline_conf, true);
+
     if args.debug {
         log::debug!("Conf file includes {} items and description is {:?}",
             conf.items.len(),
             conf.description
         );
+        for item in &conf.items {
+            log::debug!("Rule from {}: {:?}",
+                item.source,
+                item.regex.as_ref().map(|r| r.as_str())
+            );
+        }
     }
 
-    let conf_items = &conf.items;
-    let stdin = stdin();
-    for line in stdin.lock().lines() {
+    let mut colorizer = Colorizer::new(conf);
+    colorizer.set_color_depth(args.color_depth.unwrap_or_else(CgrcColorDepth::detect));
+    colorizer.set_ansi_policy(args.ansi);
+    colorizer.set_sanitize(args.sanitize);
+    // Clean text has no escape sequences at all, not even the colors.
+    let color_mode = match args.strip_ansi {
+        true => {
+            colorizer.set_ansi_policy(CgrcAnsiPolicy::CgrcAnsiStrip);
+            colorizer.set_sanitize(CgrcSanitizeMode::CgrcSanitizeRemove);
+            CgrcColorMode::CgrcColorNever
+        },
+        false => args.color
+    };
+    colorizer.set_debug(args.debug);
+
+    let title = match args_confs.is_empty() {
+        true => String::from("cgrc"),
+        false => format!("cgrc {}", args_confs.join(" "))
+    };
+    if let Some(path) = &args.render_svg {
+        if !args.command.is_empty() {
+            eprintln!("--render-svg reads the text from stdin and cannot run a command");
+            process::exit(1);
+        }
+
+        let renderer = CgrcSvgRenderer::new(args.svg_theme);
+        colorizer.set_colorize(color_mode != CgrcColorMode::CgrcColorNever);
+        let lines = CgrcLineReader::new(stdin().lock(), args.input_encoding);
+        process::exit(render_svg(&mut colorizer, lines, &renderer, &title, path));
+    }
+
+    // HTML is colored also when written to a file, unless disabled. A
+    // page is always HTML.
+    let (color_mode, page) = match args.output_format {
+        CgrcOutputFormat::CgrcOutputAnsi if args.html_page.is_none() => (color_mode, None),
+        _ => {
+            let renderer = CgrcHtmlRenderer::new(args.html_page.unwrap_or_default());
+            colorizer.set_renderer(Arc::new(renderer));
+            let color_mode = match color_mode {
+                CgrcColorMode::CgrcColorAuto => CgrcColorMode::CgrcColorAlways,
+                v => v
+            };
+            (color_mode, args.html_page.map(|_| renderer))
+        }
+    };
+    if let Some(page) = &page {
+        println!("{}", page.page_header(&title));
+    }
+
+    if !args.command.is_empty() {
+        let status = CGRCRunner::run(&colorizer, &args.command, color_mode);
+        if let Some(page) = &page {
+            println!("{}", page.page_footer());
+        }
+        process::exit(status);
+    }
+
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+    }
+
+    colorizer.set_colorize(color_mode.should_colorize(stdout().is_terminal()));
+    let jobs = match args.jobs {
+        Some(v) => v,
+        None if stdin_is_file() => thread::available_parallelism().map_or(1, |v| v.get()),
+        None => 1
+    };
+    let lines = CgrcLineReader::new(stdin().lock(), args.input_encoding);
+    if jobs > 1 && !colorizer.is_stateful() {
+        colorize_batches(&mut colorizer, lines, jobs);
+    }
+    else {
+        if jobs > 1 {
+            log::info!("Rules carry state between lines, lines are processed sequentially");
+        }
+        colorize_sequentially(&mut colorizer, lines);
+    }
+
+    if let Some(page) = &page {
+        println!("{}", page.page_footer());
+    }
+}
+
+// Private portion
+// ===============
+const BATCH_LINES: usize = 16384;
+
+fn stdin_is_file() -> bool {
+    match stdin().as_fd().try_clone_to_owned() {
+        Err(_) => false,
+        Ok(fd) => File::from(fd).metadata().is_ok_and(|m| m.is_file())
+    }
+}
+
+fn colorize_sequentially<R: std::io::BufRead>(colorizer: &mut Colorizer, lines: CgrcLineReader<R>) {
+    let mut out = stdout().lock();
+    for line in lines {
         let line = match line {
             Ok(v) => v,
             Err(e) => {
-                println!("Error: {}", e.to_string());
-                continue
+                eprintln!("Error: {}", e);
+                break
             }
         };
-        let formatted = CGRCParser::parse_log_line(
-            &conf_items,
-            &line,
-            args.debug
-        );
-        
-        if let Some(formatted) = formatted {
-            println!("{}", formatted);
+        if let Some(formatted) = colorizer.colorize_line(&line.text) {
+            if write_line(&mut out, &line, &formatted).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn render_svg<R: std::io::BufRead>(colorizer: &mut Colorizer,
+                                    lines: CgrcLineReader<R>,
+                                    renderer: &CgrcSvgRenderer,
+                                    title: &str,
+                                    path: &Path) -> i32 {
+    let mut input: Vec<CgrcInputLine> = vec![];
+    for line in lines {
+        match line {
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            },
+            Ok(v) => input.push(v)
+        }
+    }
+
+    let styled: Vec<CgrcStyledLine> = input.iter()
+        .filter_map(|line| colorizer.style_line(&line.text))
+        .collect();
+    match fs::write(path, renderer.document(title, &styled)) {
+        Err(e) => {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+            1
+        },
+        Ok(_) => 0
+    }
+}
+
+fn write_line<W: Write>(out: &mut W, line: &CgrcInputLine, formatted: &str) -> std::io::Result<()> {
+    out.write_all(&line.restore_bytes(formatted))?;
+    out.write_all(b"\n")?;
+    out.flush()
+}
+
+fn colorize_batches<R: std::io::BufRead>(colorizer: &mut Colorizer, mut lines: CgrcLineReader<R>, jobs: usize) {
+    let mut out = BufWriter::new(stdout().lock());
+    let mut batch: Vec<CgrcInputLine> = Vec::with_capacity(BATCH_LINES);
+    let mut done = false;
+    while !done {
+        batch.clear();
+        for line in lines.by_ref().take(BATCH_LINES) {
+            match line {
+                Ok(v) => batch.push(v),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    done = true;
+                    break;
+                }
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+        done |= batch.len() < BATCH_LINES;
+
+        let formatted = colorizer.colorize_lines(&batch, jobs);
+        for (line, formatted) in batch.iter().zip(formatted) {
+            if let Some(formatted) = formatted {
+                if out.write_all(&line.restore_bytes(&formatted)).is_err() || out.write_all(b"\n").is_err() {
+                    return;
+                }
+            }
+        }
+        if out.flush().is_err() {
+            return;
         }
     }
 }